@@ -0,0 +1,128 @@
+#!/usr/bin/env python3
+# mock openid connect provider to try the console's oidc login locally (python 3 stdlib only)
+#
+#   scripts/mock-oidc.py --port 9000 --client-id console --client-secret secret --user alice --groups admins
+#
+# config: "oidc": { "issuer": "http://127.0.0.1:9000", "client_id": "console", "client_secret": "secret",
+#                   "redirect_uri": "https://127.0.0.1:8443/webconsole/oidc/callback",
+#                   "role_claim": "groups", "admin_values": ["admins"] }
+#
+# every authorization request is approved for --user (or ?login_hint=name), the id token is HS256
+# signed with the client secret. pkce (S256), the redirect uri and the client credentials are checked
+
+import argparse
+import base64
+import hashlib
+import hmac
+import json
+import secrets
+import time
+from http.server import BaseHTTPRequestHandler, ThreadingHTTPServer
+from urllib.parse import parse_qs, urlencode, urlparse
+
+args = None
+codes = {}
+
+
+def b64(data):
+    return base64.urlsafe_b64encode(data).rstrip(b"=").decode()
+
+
+def id_token(user, nonce):
+    now = int(time.time())
+    header = {"alg": "HS256", "typ": "JWT"}
+    claims = {
+        "iss": args.issuer,
+        "sub": "mock-" + user,
+        "aud": args.client_id,
+        "iat": now,
+        "exp": now + 300,
+        "nonce": nonce,
+        "preferred_username": user,
+        "email": user + "@example.com",
+        "groups": args.groups,
+    }
+    signing_input = b64(json.dumps(header).encode()) + "." + b64(json.dumps(claims).encode())
+    signature = hmac.new(args.client_secret.encode(), signing_input.encode(), hashlib.sha256).digest()
+    return signing_input + "." + b64(signature)
+
+
+class Handler(BaseHTTPRequestHandler):
+    def reply(self, status, body=None, headers=None):
+        self.send_response(status)
+        for name, value in (headers or {}).items():
+            self.send_header(name, value)
+        data = json.dumps(body).encode() if body is not None else b""
+        if body is not None:
+            self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(data)))
+        self.end_headers()
+        self.wfile.write(data)
+
+    def do_GET(self):
+        url = urlparse(self.path)
+        query = {k: v[0] for k, v in parse_qs(url.query).items()}
+        if url.path == "/.well-known/openid-configuration":
+            return self.reply(200, {
+                "issuer": args.issuer,
+                "authorization_endpoint": args.issuer + "/authorize",
+                "token_endpoint": args.issuer + "/token",
+                "jwks_uri": args.issuer + "/jwks",
+                "response_types_supported": ["code"],
+                "code_challenge_methods_supported": ["S256"],
+                "id_token_signing_alg_values_supported": ["HS256"],
+            })
+        if url.path == "/jwks":
+            return self.reply(200, {"keys": []})
+        if url.path == "/authorize":
+            if query.get("client_id") != args.client_id or query.get("response_type") != "code":
+                return self.reply(400, {"error": "invalid_request"})
+            if query.get("code_challenge_method") != "S256" or not query.get("code_challenge"):
+                return self.reply(400, {"error": "invalid_request", "error_description": "pkce S256 required"})
+            code = secrets.token_urlsafe(24)
+            codes[code] = {
+                "user": query.get("login_hint", args.user),
+                "nonce": query.get("nonce", ""),
+                "challenge": query["code_challenge"],
+                "redirect_uri": query["redirect_uri"],
+            }
+            location = query["redirect_uri"] + "?" + urlencode({"code": code, "state": query.get("state", "")})
+            return self.reply(302, headers={"Location": location})
+        self.reply(404, {"error": "not_found"})
+
+    def do_POST(self):
+        if urlparse(self.path).path != "/token":
+            return self.reply(404, {"error": "not_found"})
+        length = int(self.headers.get("Content-Length", 0))
+        form = {k: v[0] for k, v in parse_qs(self.rfile.read(length).decode()).items()}
+        auth = self.headers.get("Authorization", "")
+        expected = base64.b64encode(f"{args.client_id}:{args.client_secret}".encode()).decode()
+        if auth != "Basic " + expected and form.get("client_secret") != args.client_secret:
+            return self.reply(401, {"error": "invalid_client"})
+        grant = codes.pop(form.get("code", ""), None)
+        if grant is None or form.get("grant_type") != "authorization_code":
+            return self.reply(400, {"error": "invalid_grant"})
+        if form.get("redirect_uri") != grant["redirect_uri"]:
+            return self.reply(400, {"error": "invalid_grant", "error_description": "redirect_uri mismatch"})
+        challenge = b64(hashlib.sha256(form.get("code_verifier", "").encode()).digest())
+        if challenge != grant["challenge"]:
+            return self.reply(400, {"error": "invalid_grant", "error_description": "pkce verification failed"})
+        self.reply(200, {
+            "access_token": secrets.token_urlsafe(24),
+            "token_type": "Bearer",
+            "expires_in": 300,
+            "id_token": id_token(grant["user"], grant["nonce"]),
+        })
+
+
+if __name__ == "__main__":
+    parser = argparse.ArgumentParser(description="mock openid connect provider")
+    parser.add_argument("--port", type=int, default=9000)
+    parser.add_argument("--client-id", default="console")
+    parser.add_argument("--client-secret", default="secret")
+    parser.add_argument("--user", default="alice")
+    parser.add_argument("--groups", nargs="*", default=[])
+    args = parser.parse_args()
+    args.issuer = f"http://127.0.0.1:{args.port}"
+    print(f"mock oidc provider on {args.issuer}")
+    ThreadingHTTPServer(("127.0.0.1", args.port), Handler).serve_forever()
@@ -0,0 +1,44 @@
+#!/bin/bash
+# runs the ignored acme integration test against a local pebble (podman or docker), the
+# image has to be pulled beforehand, nothing is fetched while the test runs
+#
+#   podman pull ghcr.io/letsencrypt/pebble:latest
+#   scripts/pebble.sh
+#
+# the test orders a certificate for localhost with the http-01 challenge, pebble validates it
+# against the test's challenge listener on port 5002 (pebble's httpPort)
+
+set -euo pipefail
+
+ENGINE="${ENGINE:-$(command -v podman || command -v docker)}"
+IMAGE="${PEBBLE_IMAGE:-ghcr.io/letsencrypt/pebble:latest}"
+NAME="ai-webconsole-pebble"
+WORK="$(mktemp -d)"
+
+cleanup() {
+  "${ENGINE}" rm -f "${NAME}" >/dev/null 2>&1 || true
+  rm -rf "${WORK}"
+}
+trap cleanup EXIT
+
+if ! "${ENGINE}" image inspect "${IMAGE}" >/dev/null 2>&1; then
+  echo "image ${IMAGE} not found, pull it first" >&2
+  exit 1
+fi
+
+# host networking so the validation of localhost reaches the test's challenge listener
+"${ENGINE}" run -d --rm --pull=never --name "${NAME}" --network host \
+  -e PEBBLE_VA_NOSLEEP=1 -e PEBBLE_WFE_NONCEREJECT=0 "${IMAGE}" >/dev/null
+
+# pebble's directory is served with a certificate from its own test ca, shipped in the image
+"${ENGINE}" cp "${NAME}:/test/certs/pebble.minica.pem" "${WORK}/pebble.minica.pem"
+
+for _ in $(seq 1 30); do
+  curl -sk -o /dev/null https://localhost:14000/dir && break
+  sleep 1
+done
+
+PEBBLE_DIRECTORY=https://localhost:14000/dir \
+PEBBLE_CA="${WORK}/pebble.minica.pem" \
+ACME_DOMAIN="${ACME_DOMAIN:-localhost}" \
+  cargo test -- --ignored pebble_issues_certificate
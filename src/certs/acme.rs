@@ -0,0 +1,611 @@
+use crate::certs::controller::{error, load_private_key, load_public_key, write_key_pair};
+use crate::certs::resolver::{CertResolver, certified_key};
+use crate::config::process::Acme;
+use aws_lc_rs::rand::SystemRandom;
+use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use custom_logger as log;
+use http::{Method, Request, Response, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::HeaderMap;
+use hyper::body::{Bytes, Incoming};
+use hyper::service::service_fn;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use rcgen::{CertificateParams, CustomExtension, PKCS_ECDSA_P256_SHA256};
+use rustls::{ClientConfig, RootCertStore};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use serde_derive::Deserialize;
+use serde_json::{Value, json};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+type AcmeResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// how often the certificate expiry is checked, and the retry delay after a failed order
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const POLL_ATTEMPTS: u32 = 30;
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+}
+
+struct AcmeClient {
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    directory: Directory,
+    key: EcdsaKeyPair,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+/// obtains the certificate on first start and renews it before it expires,
+/// ssl.cert/ssl.key in certs_dir are replaced and swapped into the resolver
+pub async fn watch_acme(
+    acme: Acme,
+    certs_dir: String,
+    domains: Vec<String>,
+    resolver: Arc<CertResolver>,
+) {
+    let renew_days = acme.renew_days.unwrap_or(30);
+    loop {
+        let cert_path = format!("{}/ssl.cert", certs_dir);
+        let wait = match needs_renewal(&cert_path, renew_days) {
+            false => CHECK_INTERVAL,
+            true => match issue(&acme, &certs_dir, &domains, &resolver).await {
+                Ok(()) => {
+                    log::info!("acme certificate issued for {:?}", domains);
+                    CHECK_INTERVAL
+                }
+                Err(e) => {
+                    log::error!("acme certificate order failed: {}", e);
+                    RETRY_INTERVAL
+                }
+            },
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+// the self signed placeholder (issuer equals subject) and certificates close to expiry are renewed
+fn needs_renewal(cert_path: &str, renew_days: i64) -> bool {
+    let certs = match load_public_key(cert_path.to_string()) {
+        Ok(certs) => certs,
+        Err(_) => return true,
+    };
+    let Some(cert) = certs.first() else {
+        return true;
+    };
+    let Ok((_, x509)) = X509Certificate::from_der(cert.as_ref()) else {
+        return true;
+    };
+    if x509.issuer() == x509.subject() {
+        return true;
+    }
+    let remaining = x509.validity().not_after.timestamp() - chrono::Utc::now().timestamp();
+    remaining < renew_days * 24 * 60 * 60
+}
+
+async fn issue(
+    acme: &Acme,
+    certs_dir: &str,
+    domains: &[String],
+    resolver: &CertResolver,
+) -> AcmeResult<()> {
+    let mut client = AcmeClient::new(acme, certs_dir).await?;
+    client
+        .account(acme.contact.clone().unwrap_or_default())
+        .await?;
+
+    let identifiers: Vec<Value> = domains
+        .iter()
+        .map(|d| json!({"type": "dns", "value": d}))
+        .collect();
+    let (headers, body) = client
+        .post(
+            &client.directory.new_order.clone(),
+            Some(json!({"identifiers": identifiers})),
+        )
+        .await?;
+    let order_url = location(&headers)?;
+    let order: Order = serde_json::from_slice(&body)?;
+
+    let challenge_type = acme.challenge.clone().unwrap_or("http-01".to_string());
+    let tokens: Arc<RwLock<HashMap<String, String>>> = Arc::new(RwLock::new(HashMap::new()));
+    let http_server = match challenge_type.as_str() {
+        "http-01" => Some(serve_http01(acme.http_port.unwrap_or(80), tokens.clone()).await?),
+        "tls-alpn-01" => None,
+        &_ => return Err(format!("acme challenge {} not available", challenge_type).into()),
+    };
+    let res = client
+        .authorize(&order, &challenge_type, &tokens, resolver)
+        .await;
+    if let Some(http_server) = http_server {
+        http_server.abort();
+    }
+    res?;
+
+    // finalize with a csr for a fresh key, then wait for the certificate
+    let key_pair = rcgen::KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+    let csr = CertificateParams::new(domains.to_vec())?.serialize_request(&key_pair)?;
+    client
+        .post(
+            &order.finalize,
+            Some(json!({"csr": URL_SAFE_NO_PAD.encode(csr.der())})),
+        )
+        .await?;
+    let mut certificate_url = None;
+    for _ in 0..POLL_ATTEMPTS {
+        let (_, body) = client.post(&order_url, None).await?;
+        let order: Order = serde_json::from_slice(&body)?;
+        match order.status.as_str() {
+            "valid" => {
+                certificate_url = order.certificate;
+                break;
+            }
+            "invalid" => return Err("acme order is invalid".into()),
+            _ => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+    let certificate_url = certificate_url.ok_or("acme order did not complete in time")?;
+    let (_, cert_pem) = client.post(&certificate_url, None).await?;
+    let cert_pem = String::from_utf8(cert_pem.to_vec())?;
+
+    write_key_pair(certs_dir, &cert_pem, &key_pair.serialize_pem())?;
+    let certs = load_public_key(format!("{}/ssl.cert", certs_dir))?;
+    let key = load_private_key(format!("{}/ssl.key", certs_dir), None)?;
+    resolver.swap_default(certified_key(certs, key, "acme")?);
+    Ok(())
+}
+
+impl AcmeClient {
+    async fn new(acme: &Acme, certs_dir: &str) -> AcmeResult<Self> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        if let Some(ca_bundle) = &acme.ca_bundle {
+            for cert in load_public_key(ca_bundle.clone())? {
+                roots.add(cert)?;
+            }
+        }
+        let tls = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(tls)
+            .https_or_http()
+            .enable_http1()
+            .build();
+        let client = Client::builder(TokioExecutor::new()).build(connector);
+
+        let req = Request::get(&acme.directory_url).body(Full::default())?;
+        let res = client.request(req).await?;
+        if !res.status().is_success() {
+            return Err(format!("acme directory returned {}", res.status()).into());
+        }
+        let body = res.into_body().collect().await?.to_bytes();
+        let directory: Directory = serde_json::from_slice(&body)?;
+        Ok(AcmeClient {
+            client,
+            directory,
+            key: account_key(certs_dir)?,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    // registers the account key, an existing account is returned for a known key
+    async fn account(&mut self, contact: Vec<String>) -> AcmeResult<()> {
+        let payload = json!({"termsOfServiceAgreed": true, "contact": contact});
+        let (headers, _) = self
+            .post(&self.directory.new_account.clone(), Some(payload))
+            .await?;
+        self.kid = Some(location(&headers)?);
+        Ok(())
+    }
+
+    async fn authorize(
+        &mut self,
+        order: &Order,
+        challenge_type: &str,
+        tokens: &RwLock<HashMap<String, String>>,
+        resolver: &CertResolver,
+    ) -> AcmeResult<()> {
+        for authz_url in &order.authorizations {
+            let (_, body) = self.post(authz_url, None).await?;
+            let authz: Authorization = serde_json::from_slice(&body)?;
+            if authz.status == "valid" {
+                continue;
+            }
+            let domain = authz.identifier.value.clone();
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|c| c.kind == challenge_type)
+                .ok_or(format!(
+                    "no {} challenge offered for {}",
+                    challenge_type, domain
+                ))?;
+            let key_authorization = format!("{}.{}", challenge.token, self.thumbprint());
+            match challenge_type {
+                "http-01" => {
+                    tokens
+                        .write()
+                        .unwrap()
+                        .insert(challenge.token.clone(), key_authorization);
+                }
+                _ => {
+                    let digest = Sha256::digest(key_authorization.as_bytes());
+                    resolver.set_challenge(domain.clone(), alpn_certificate(&domain, &digest)?);
+                }
+            }
+            log::info!("acme {} challenge for {}", challenge_type, domain);
+            let res = self.validate(&challenge.url, authz_url).await;
+            tokens.write().unwrap().remove(&challenge.token);
+            resolver.clear_challenge(&domain);
+            res?;
+        }
+        Ok(())
+    }
+
+    async fn validate(&mut self, challenge_url: &str, authz_url: &str) -> AcmeResult<()> {
+        self.post(challenge_url, Some(json!({}))).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            tokio::time::sleep(POLL_INTERVAL).await;
+            let (_, body) = self.post(authz_url, None).await?;
+            let authz: Value = serde_json::from_slice(&body)?;
+            match authz["status"].as_str().unwrap_or_default() {
+                "valid" => return Ok(()),
+                "pending" | "processing" => {}
+                status => {
+                    return Err(format!("acme authorization {}: {}", status, authz).into());
+                }
+            }
+        }
+        Err("acme authorization did not complete in time".into())
+    }
+
+    // signed (JWS) POST, a None payload is a POST-as-GET
+    async fn post(&mut self, url: &str, payload: Option<Value>) -> AcmeResult<(HeaderMap, Bytes)> {
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let body = jws(&self.key, &protected, payload.as_ref())?;
+            let req = Request::post(url)
+                .header("content-type", "application/jose+json")
+                .body(Full::from(serde_json::to_vec(&body)?))?;
+            let res = self.client.request(req).await?;
+            self.nonce = nonce_header(res.headers());
+            let status = res.status();
+            let headers = res.headers().clone();
+            let body = res.into_body().collect().await?.to_bytes();
+            if status.is_success() {
+                return Ok((headers, body));
+            }
+            // a stale nonce is retried once with the fresh one from the error response
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if !retried && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                retried = true;
+                continue;
+            }
+            return Err(format!("acme request {} returned {}: {}", url, status, problem).into());
+        }
+    }
+
+    async fn new_nonce(&self) -> AcmeResult<String> {
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.directory.new_nonce)
+            .body(Full::default())?;
+        let res = self.client.request(req).await?;
+        Ok(nonce_header(res.headers()).ok_or("acme server did not return a nonce")?)
+    }
+
+    // base64url x and y of the uncompressed point 0x04 || x || y
+    fn coordinates(&self) -> (String, String) {
+        let public = self.key.public_key().as_ref();
+        (
+            URL_SAFE_NO_PAD.encode(&public[1..33]),
+            URL_SAFE_NO_PAD.encode(&public[33..65]),
+        )
+    }
+
+    fn jwk(&self) -> Value {
+        let (x, y) = self.coordinates();
+        json!({"crv": "P-256", "kty": "EC", "x": x, "y": y})
+    }
+
+    fn thumbprint(&self) -> String {
+        let (x, y) = self.coordinates();
+        jwk_thumbprint(&x, &y)
+    }
+}
+
+// flattened JWS (RFC 7515), ES256 over protected.payload with the account key
+fn jws(key: &EcdsaKeyPair, protected: &Value, payload: Option<&Value>) -> AcmeResult<Value> {
+    let protected = URL_SAFE_NO_PAD.encode(serde_json::to_vec(protected)?);
+    let payload = match payload {
+        Some(payload) => URL_SAFE_NO_PAD.encode(serde_json::to_vec(payload)?),
+        None => String::new(),
+    };
+    let signature = key
+        .sign(
+            &SystemRandom::new(),
+            format!("{}.{}", protected, payload).as_bytes(),
+        )
+        .map_err(|_| "failed to sign acme request")?;
+    Ok(json!({
+        "protected": protected,
+        "payload": payload,
+        "signature": URL_SAFE_NO_PAD.encode(signature.as_ref()),
+    }))
+}
+
+// RFC 7638, the required members in lexicographic order without whitespace, written out by hand
+// as serde_json (preserve_order) keeps the insertion order (base64url needs no escaping)
+fn jwk_thumbprint(x: &str, y: &str) -> String {
+    let jwk = format!(
+        "{{\"crv\":\"P-256\",\"kty\":\"EC\",\"x\":\"{}\",\"y\":\"{}\"}}",
+        x, y
+    );
+    URL_SAFE_NO_PAD.encode(Sha256::digest(jwk.as_bytes()))
+}
+
+// the account key is kept in certs_dir so renewals reuse the same account
+fn account_key(certs_dir: &str) -> AcmeResult<EcdsaKeyPair> {
+    let path = format!("{}/acme-account.key", certs_dir);
+    let key_pair = match fs::read_to_string(&path) {
+        Ok(pem) => rcgen::KeyPair::from_pem(&pem)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::info!("creating acme account key {}", path);
+            let key_pair = rcgen::KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+            fs::create_dir_all(certs_dir)?;
+            write_private(&path, &key_pair.serialize_pem())?;
+            key_pair
+        }
+        Err(e) => return Err(error(format!("failed to read {}: {}", path, e)).into()),
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &key_pair.serialize_der())
+        .map_err(|e| format!("invalid acme account key {}: {}", path, e).into())
+}
+
+fn write_private(path: &str, pem: &str) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(pem.as_bytes())
+}
+
+// self signed certificate carrying the acmeIdentifier extension (RFC 8737),
+// built without the key match check as webpki rejects the unknown critical extension
+fn alpn_certificate(domain: &str, digest: &[u8]) -> AcmeResult<rustls::sign::CertifiedKey> {
+    let key_pair = rcgen::KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)?;
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let cert = params.self_signed(&key_pair)?;
+    let certs = vec![CertificateDer::from(cert.der().to_vec())];
+    let key = PrivateKeyDer::try_from(key_pair.serialize_der())?;
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    let signing_key = provider.key_provider.load_private_key(key)?;
+    Ok(rustls::sign::CertifiedKey::new(certs, signing_key))
+}
+
+// answers /.well-known/acme-challenge/{token} while an http-01 order is in progress
+async fn serve_http01(
+    port: u16,
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+) -> AcmeResult<tokio::task::JoinHandle<()>> {
+    let addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), port);
+    let listener = TcpListener::bind(addr).await.map_err(|e| {
+        error(format!(
+            "failed to bind acme http-01 listener {}: {}",
+            addr, e
+        ))
+    })?;
+    Ok(tokio::spawn(async move {
+        loop {
+            let Ok((tcp_stream, _)) = listener.accept().await else {
+                continue;
+            };
+            let tokens = tokens.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let tokens = tokens.clone();
+                    async move { Ok::<_, hyper::Error>(http01_response(req, &tokens)) }
+                });
+                let _ = Builder::new(TokioExecutor::new())
+                    .serve_connection(TokioIo::new(tcp_stream), service)
+                    .await;
+            });
+        }
+    }))
+}
+
+fn http01_response(
+    req: Request<Incoming>,
+    tokens: &RwLock<HashMap<String, String>>,
+) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    let key_authorization = req
+        .uri()
+        .path()
+        .strip_prefix("/.well-known/acme-challenge/")
+        .and_then(|token| tokens.read().unwrap().get(token).cloned());
+    match key_authorization {
+        Some(key_authorization) => {
+            *response.status_mut() = StatusCode::OK;
+            *response.body_mut() = Full::from(key_authorization);
+        }
+        None => {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+    }
+    response
+}
+
+fn nonce_header(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get("replay-nonce")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
+}
+
+fn location(headers: &HeaderMap) -> AcmeResult<String> {
+    Ok(headers
+        .get("location")
+        .and_then(|v| v.to_str().ok())
+        .ok_or("acme response without location header")?
+        .to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::certs::resolver::certified_key;
+    use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+
+    fn test_key() -> EcdsaKeyPair {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap()
+    }
+
+    fn decode(value: &Value) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(value.as_str().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn thumbprint_of_rfc7517_example_key() {
+        // the P-256 key of RFC 7517 appendix A.1
+        let thumbprint = jwk_thumbprint(
+            "MKBCTNIcKUSDii11ySs3526iDZ8AiTo7Tu6KPAqv7D4",
+            "4Etl6SRW2YiLUrN5vfvVHuhp7x8PxltmWWlbbM4IFyM",
+        );
+        assert_eq!(thumbprint, "cn-I_WNMClehiVp51i_0VpOENW1upEerA8sEam5hn-s");
+    }
+
+    #[test]
+    fn jws_signature_verifies() {
+        let key = test_key();
+        let protected = json!({"alg": "ES256", "nonce": "abc", "url": "https://acme/new-order"});
+        let payload = json!({"identifiers": [{"type": "dns", "value": "example.com"}]});
+        let body = jws(&key, &protected, Some(&payload)).unwrap();
+        let signed = format!(
+            "{}.{}",
+            body["protected"].as_str().unwrap(),
+            body["payload"].as_str().unwrap()
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, key.public_key().as_ref())
+            .verify(signed.as_bytes(), &decode(&body["signature"]))
+            .unwrap();
+        let decoded: Value = serde_json::from_slice(&decode(&body["protected"])).unwrap();
+        assert_eq!(decoded, protected);
+        let decoded: Value = serde_json::from_slice(&decode(&body["payload"])).unwrap();
+        assert_eq!(decoded, payload);
+    }
+
+    #[test]
+    fn jws_post_as_get_has_empty_payload() {
+        let key = test_key();
+        let body = jws(&key, &json!({"alg": "ES256"}), None).unwrap();
+        assert_eq!(body["payload"], "");
+        assert_eq!(decode(&body["signature"]).len(), 64);
+    }
+
+    // needs a running pebble, scripts/pebble.sh starts one and runs this test
+    // (PEBBLE_DIRECTORY, PEBBLE_CA and ACME_DOMAIN override the defaults)
+    #[tokio::test]
+    #[ignore]
+    async fn pebble_issues_certificate() {
+        let env = |name: &str, default: &str| std::env::var(name).unwrap_or(default.to_string());
+        let acme = Acme {
+            directory_url: env("PEBBLE_DIRECTORY", "https://localhost:14000/dir"),
+            challenge: Some("http-01".to_string()),
+            http_port: Some(5002),
+            contact: Some(vec!["mailto:test@example.com".to_string()]),
+            ca_bundle: Some(env("PEBBLE_CA", "pebble.minica.pem")),
+            renew_days: None,
+        };
+        let domains = vec![env("ACME_DOMAIN", "localhost")];
+        let certs_dir = std::env::temp_dir()
+            .join(format!("ai-webconsole-pebble-{}", std::process::id()))
+            .to_string_lossy()
+            .to_string();
+        let placeholder = rcgen::generate_simple_self_signed(domains.clone()).unwrap();
+        let resolver = CertResolver::new(
+            certified_key(
+                vec![placeholder.cert.der().clone()],
+                PrivateKeyDer::try_from(placeholder.key_pair.serialize_der()).unwrap(),
+                "placeholder",
+            )
+            .unwrap(),
+            HashMap::new(),
+        );
+        let res = issue(&acme, &certs_dir, &domains, &resolver).await;
+        let cert_path = format!("{}/ssl.cert", certs_dir);
+        let renewal = needs_renewal(&cert_path, 0);
+        let _ = fs::remove_dir_all(&certs_dir);
+        res.unwrap();
+        assert!(
+            !renewal,
+            "the issued certificate is not signed by the acme ca"
+        );
+    }
+}
@@ -0,0 +1,65 @@
+use crate::certs::controller::error;
+use rustls::RootCertStore;
+use rustls::server::WebPkiClientVerifier;
+use rustls::server::danger::ClientCertVerifier;
+use rustls_pki_types::CertificateDer;
+use std::fs;
+use std::io;
+use std::sync::Arc;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// console user derived from a verified client certificate,
+/// inserted into the request extensions for the handlers
+#[derive(Clone, Debug)]
+pub struct ClientUser(pub String);
+
+/// builds the client certificate verifier from the ca bundle,
+/// mode "optional" still accepts clients without a certificate
+pub fn client_verifier(client_ca: String, mode: String) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let cafile = fs::File::open(&client_ca)
+        .map_err(|e| error(format!("failed to open {}: {}", client_ca, e)))?;
+    let mut reader = io::BufReader::new(cafile);
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut reader) {
+        roots
+            .add(cert?)
+            .map_err(|e| error(format!("invalid ca certificate in {}: {}", client_ca, e)))?;
+    }
+    if roots.is_empty() {
+        return Err(error(format!("no ca certificates found in {}", client_ca)));
+    }
+    let builder = WebPkiClientVerifier::builder(Arc::new(roots));
+    let builder = match mode.as_str() {
+        "required" => builder,
+        "optional" => builder.allow_unauthenticated(),
+        &_ => return Err(error(format!("client_auth {} not available", mode))),
+    };
+    builder
+        .build()
+        .map_err(|e| error(format!("failed to build client verifier: {}", e)))
+}
+
+/// maps the certificate subject to a user, the common name is used
+/// and the first email or dns subject alternative name is the fallback
+pub fn client_user(cert: &CertificateDer<'_>) -> Option<ClientUser> {
+    let (_, x509) = X509Certificate::from_der(cert.as_ref()).ok()?;
+    let cn = x509
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+        .map(|cn| cn.to_string());
+    if let Some(cn) = cn {
+        return Some(ClientUser(cn));
+    }
+    let san = x509.subject_alternative_name().ok()??;
+    san.value
+        .general_names
+        .iter()
+        .find_map(|name| match name {
+            GeneralName::RFC822Name(n) | GeneralName::DNSName(n) => Some(n.to_string()),
+            _ => None,
+        })
+        .map(ClientUser)
+}
@@ -1 +1,4 @@
+pub mod acme;
+pub mod client;
 pub mod controller;
+pub mod resolver;
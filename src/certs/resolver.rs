@@ -0,0 +1,209 @@
+use crate::certs::controller::{
+    CertificateInterface, ImplCertificateInterface, error, load_private_key, load_public_key,
+};
+use crate::config::process::CertHost;
+use custom_logger as log;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+
+pub const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+#[derive(Debug)]
+struct Certificates {
+    default: Arc<CertifiedKey>,
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+}
+
+/// hands out the certificate for each new handshake, picked by SNI hostname
+/// with the default as fallback, a reload swaps them without touching established connections
+#[derive(Debug)]
+pub struct CertResolver {
+    current: RwLock<Arc<Certificates>>,
+    // acme tls-alpn-01 validation certificates keyed by domain
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn new(default: CertifiedKey, hosts: HashMap<String, CertifiedKey>) -> Self {
+        CertResolver {
+            current: RwLock::new(Arc::new(certificates(default, hosts))),
+            challenges: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub fn swap(&self, default: CertifiedKey, hosts: HashMap<String, CertifiedKey>) {
+        *self.current.write().unwrap() = Arc::new(certificates(default, hosts));
+    }
+
+    // replaces the default certificate only, the hostname certificates are kept
+    pub fn swap_default(&self, default: CertifiedKey) {
+        let mut current = self.current.write().unwrap();
+        *current = Arc::new(Certificates {
+            default: Arc::new(default),
+            hosts: current.hosts.clone(),
+        });
+    }
+
+    pub fn set_challenge(&self, domain: String, key: CertifiedKey) {
+        self.challenges
+            .write()
+            .unwrap()
+            .insert(domain.to_lowercase(), Arc::new(key));
+    }
+
+    pub fn clear_challenge(&self, domain: &str) {
+        self.challenges
+            .write()
+            .unwrap()
+            .remove(&domain.to_lowercase());
+    }
+}
+
+fn certificates(default: CertifiedKey, hosts: HashMap<String, CertifiedKey>) -> Certificates {
+    Certificates {
+        default: Arc::new(default),
+        hosts: hosts
+            .into_iter()
+            .map(|(hostname, key)| (hostname.to_lowercase(), Arc::new(key)))
+            .collect(),
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        if client_hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN))
+        {
+            let server_name = client_hello.server_name()?.to_lowercase();
+            return self.challenges.read().unwrap().get(&server_name).cloned();
+        }
+        let certificates = self.current.read().unwrap().clone();
+        if let Some(server_name) = client_hello.server_name() {
+            let server_name = server_name.to_lowercase();
+            // exact match first, then a wildcard entry for the parent domain
+            let wildcard = server_name
+                .split_once('.')
+                .map(|(_, parent)| format!("*.{}", parent));
+            let found = certificates.hosts.get(&server_name).or_else(|| {
+                wildcard
+                    .as_ref()
+                    .and_then(|wildcard| certificates.hosts.get(wildcard))
+            });
+            if let Some(key) = found {
+                return Some(key.clone());
+            }
+        }
+        Some(certificates.default.clone())
+    }
+}
+
+pub fn certified_key(
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    name: &str,
+) -> io::Result<CertifiedKey> {
+    let provider = rustls::crypto::aws_lc_rs::default_provider();
+    CertifiedKey::from_der(certs, key, &provider).map_err(|e| {
+        error(format!(
+            "certificate and private key for {} do not match: {}",
+            name, e
+        ))
+    })
+}
+
+/// loads the certificate chain and private key, failing if they don't belong together
+pub async fn load_certified_key(impl_certs: &ImplCertificateInterface) -> io::Result<CertifiedKey> {
+    let certs = impl_certs.get_public_cert().await?;
+    let key = impl_certs.get_private_cert().await?;
+    certified_key(certs, key, "default")
+}
+
+/// loads the cert/key pair of every configured hostname, failing on the first bad pair
+pub fn load_host_keys(
+    hosts: &[CertHost],
+    passphrase: Option<&str>,
+) -> io::Result<HashMap<String, CertifiedKey>> {
+    let mut keys = HashMap::new();
+    for host in hosts {
+        let certs = load_public_key(host.cert.clone())?;
+        let key = load_private_key(host.key.clone(), passphrase)?;
+        keys.insert(
+            host.hostname.clone(),
+            certified_key(certs, key, &host.hostname)?,
+        );
+    }
+    Ok(keys)
+}
+
+// modification times of the default and all hostname certificate files
+fn modified(impl_certs: &ImplCertificateInterface, hosts: &[CertHost]) -> Vec<Option<SystemTime>> {
+    let mut times = vec![impl_certs.modified().map(|(cert, key)| cert.max(key))];
+    for host in hosts {
+        for path in [&host.cert, &host.key] {
+            times.push(fs::metadata(path).and_then(|m| m.modified()).ok());
+        }
+    }
+    times
+}
+
+/// reloads on SIGHUP, or when the certificate files change (polled every `interval` seconds, 0 disables polling)
+pub async fn watch_certificates(
+    impl_certs: ImplCertificateInterface,
+    hosts: Vec<CertHost>,
+    resolver: Arc<CertResolver>,
+    interval: u64,
+) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            log::error!(
+                "unable to listen for SIGHUP, certificate reload disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    let mut last_modified = modified(&impl_certs, &hosts);
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                log::info!("received SIGHUP, reloading certificates");
+            }
+            _ = ticker.tick(), if interval > 0 => {
+                if modified(&impl_certs, &hosts) == last_modified {
+                    continue;
+                }
+                log::info!("certificate files changed, reloading certificates");
+            }
+        }
+        last_modified = modified(&impl_certs, &hosts);
+        let res = match load_certified_key(&impl_certs).await {
+            Ok(default) => impl_certs
+                .passphrase()
+                .and_then(|passphrase| load_host_keys(&hosts, passphrase.as_deref()))
+                .map(|keys| (default, keys)),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok((default, keys)) => {
+                resolver.swap(default, keys);
+                log::info!("certificates reloaded");
+            }
+            Err(e) => {
+                log::error!(
+                    "failed to reload certificates, keeping the current ones: {}",
+                    e
+                );
+            }
+        }
+    }
+}
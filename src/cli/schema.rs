@@ -1,5 +1,6 @@
 // module schema
-use clap::Parser;
+use crate::config::overrides::KEYS;
+use clap::{Parser, Subcommand};
 
 /// cli struct
 #[derive(Parser, Debug)]
@@ -11,7 +12,178 @@ use clap::Parser;
     help_template = "{author-with-newline} {about-section}Version: {version} \n {usage-heading} {usage} \n {all-args} {tab}"
 )]
 pub struct Cli {
-    /// config file to use
-    #[arg(short, long, value_name = "config")]
-    pub config: String,
+    /// config file to use, json, toml or yaml (optional, AI_WEBCONSOLE_* env vars and --set override it)
+    #[arg(short, long, value_name = "config", env = "AI_WEBCONSOLE_CONFIG")]
+    pub config: Option<String>,
+
+    /// override a config field, e.g. --set port=9443 --set acme.challenge=tls-alpn-01
+    #[arg(short, long, value_name = "key=value", long_help = set_help())]
+    pub set: Vec<String>,
+
+    /// same as --set port=
+    #[arg(long, value_name = "port")]
+    pub port: Option<String>,
+
+    /// same as --set log_level= (info, debug or trace)
+    #[arg(long, value_name = "level")]
+    pub log_level: Option<String>,
+
+    /// same as --set db_path=
+    #[arg(long, value_name = "dir")]
+    pub db_path: Option<String>,
+
+    /// same as --set certs_dir=
+    #[arg(long, value_name = "dir")]
+    pub certs_dir: Option<String>,
+
+    /// same as --set deploy_dir=
+    #[arg(long, value_name = "dir")]
+    pub deploy_dir: Option<String>,
+
+    /// same as --set static_dir=
+    #[arg(long, value_name = "dir")]
+    pub static_dir: Option<String>,
+
+    /// print the effective config (secrets redacted) and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// run the console (the default without a subcommand)
+    Serve,
+    /// config file tools
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+    /// manage the users in the login tree under db_path
+    User {
+        #[command(subcommand)]
+        command: UserCommands,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommands {
+    /// convert a config file between json, toml and yaml (taken from the extensions)
+    Convert {
+        input: String,
+        output: String,
+        /// overwrite the output file
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum UserCommands {
+    /// add a user (the password is read from stdin without --password)
+    Add {
+        name: String,
+        #[arg(long)]
+        password: Option<String>,
+        /// user or admin
+        #[arg(long, default_value = "user")]
+        role: String,
+    },
+    /// set a new password
+    Passwd {
+        name: String,
+        #[arg(long)]
+        password: Option<String>,
+    },
+    /// refuse logins for the user, the record is kept
+    Disable { name: String },
+    /// allow a disabled user to log in again
+    Enable { name: String },
+    /// list the users with role and status
+    List,
+    /// set the role (user or admin)
+    Role { name: String, role: String },
+    /// allow a user registered in admin-approval mode to log in
+    Approve { name: String },
+    /// create a single use password reset token for the user
+    Reset {
+        name: String,
+        /// hours the token stays valid
+        #[arg(long, default_value_t = 24)]
+        hours: i64,
+    },
+    /// turn off two-factor authentication (lost authenticator), the user can enrol again
+    TotpReset { name: String },
+    /// lift the lockouts after failed logins
+    Unlock { name: String },
+    /// link the user to an openid connect identity, logins of that subject use the account
+    Link {
+        name: String,
+        /// the sub claim of the identity
+        #[arg(long)]
+        subject: String,
+        /// provider issuer (default oidc.issuer of the config)
+        #[arg(long)]
+        issuer: Option<String>,
+    },
+    /// create single use invite codes for the invite-code registration mode
+    Invite {
+        #[arg(long, default_value_t = 1)]
+        count: usize,
+    },
+}
+
+impl Cli {
+    /// the command line layer, the named flags go first so a --set of the same key wins
+    pub fn sets(&self) -> Vec<String> {
+        [
+            ("port", &self.port),
+            ("log_level", &self.log_level),
+            ("db_path", &self.db_path),
+            ("certs_dir", &self.certs_dir),
+            ("deploy_dir", &self.deploy_dir),
+            ("static_dir", &self.static_dir),
+        ]
+        .into_iter()
+        .filter_map(|(key, value)| value.as_ref().map(|value| format!("{}={}", key, value)))
+        .chain(self.set.iter().cloned())
+        .collect()
+    }
+}
+
+fn set_help() -> String {
+    format!(
+        "override a config field, e.g. --set port=9443 --set acme.challenge=tls-alpn-01\n\n\
+         the command line layer on top of the config file and the AI_WEBCONSOLE_* env vars, later \
+         flags win. Lists take a,b or json, listen, cert_hosts, acme and oidc take json, an empty \
+         value unsets an optional field\n\nkeys: {}",
+        KEYS.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn named_flags_come_before_set() {
+        let cli = Cli::try_parse_from([
+            "ai-webconsole",
+            "--set",
+            "port=2",
+            "--port",
+            "1",
+            "--db-path",
+            "/db",
+            "--set",
+            "log_level=debug",
+        ])
+        .unwrap();
+        assert_eq!(
+            cli.sets(),
+            vec!["port=1", "db_path=/db", "port=2", "log_level=debug"]
+        );
+    }
 }
@@ -0,0 +1,170 @@
+use crate::cli::schema::UserCommands;
+use crate::handlers::common::get_map_item;
+use crate::handlers::login::{
+    ROLES, UserData, create_invites, hash_password, list_users, read_user, token_hash, write_user,
+};
+use crate::handlers::oidc::{identity, link_user};
+use crate::handlers::request::{password_problem, username_problem};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Local;
+use std::io::{BufRead, IsTerminal, Write};
+
+// runs a user subcommand against the login tree in db_path, returns what to print
+pub async fn run(command: UserCommands) -> Result<String, Box<dyn std::error::Error>> {
+    match command {
+        UserCommands::Add {
+            name,
+            password,
+            role,
+        } => {
+            check_role(&role)?;
+            // same rules as registration, the web paths refuse any other name
+            if let Some(problem) = username_problem(&name) {
+                return Err(problem.into());
+            }
+            if read_user(&name).await?.is_some() {
+                return Err(format!("user {} exists (use user passwd or user role)", name).into());
+            }
+            let password = hash_password(&password_or_prompt(&name, password)?)?;
+            let ud = UserData::new(password, role.clone(), false);
+            write_user(&name, &ud).await?;
+            Ok(format!("user {} added with role {}", name, role))
+        }
+        UserCommands::Passwd { name, password } => {
+            let mut ud = existing(&name).await?;
+            ud.password = hash_password(&password_or_prompt(&name, password)?)?;
+            write_user(&name, &ud).await?;
+            Ok(format!("password of user {} changed", name))
+        }
+        UserCommands::Disable { name } => {
+            let mut ud = existing(&name).await?;
+            ud.disabled = true;
+            write_user(&name, &ud).await?;
+            Ok(format!("user {} disabled", name))
+        }
+        UserCommands::Enable { name } => {
+            let mut ud = existing(&name).await?;
+            ud.disabled = false;
+            write_user(&name, &ud).await?;
+            Ok(format!("user {} enabled", name))
+        }
+        UserCommands::Role { name, role } => {
+            check_role(&role)?;
+            let mut ud = existing(&name).await?;
+            ud.role = role.clone();
+            write_user(&name, &ud).await?;
+            Ok(format!("user {} has role {}", name, role))
+        }
+        UserCommands::Approve { name } => {
+            let mut ud = existing(&name).await?;
+            if !ud.pending {
+                return Err(format!("user {} is not waiting for approval", name).into());
+            }
+            ud.pending = false;
+            write_user(&name, &ud).await?;
+            Ok(format!("user {} approved", name))
+        }
+        UserCommands::Reset { name, hours } => {
+            let mut ud = existing(&name).await?;
+            let mut bytes = [0u8; 24];
+            aws_lc_rs::rand::fill(&mut bytes).map_err(|_| "unable to generate a reset token")?;
+            let token = URL_SAFE_NO_PAD.encode(bytes);
+            ud.reset_token = Some(token_hash(&token));
+            ud.reset_expires = Local::now().timestamp() + hours * 3600;
+            write_user(&name, &ud).await?;
+            Ok(token)
+        }
+        UserCommands::TotpReset { name } => {
+            let mut ud = existing(&name).await?;
+            ud.totp_secret = None;
+            ud.totp_enabled = false;
+            ud.totp_last = 0;
+            ud.recovery_codes.clear();
+            write_user(&name, &ud).await?;
+            Ok(format!(
+                "two-factor authentication of user {} turned off",
+                name
+            ))
+        }
+        UserCommands::Unlock { name } => {
+            let mut ud = existing(&name).await?;
+            ud.unlock();
+            write_user(&name, &ud).await?;
+            Ok(format!("user {} unlocked", name))
+        }
+        UserCommands::Link {
+            name,
+            subject,
+            issuer,
+        } => {
+            let mut ud = existing(&name).await?;
+            let issuer = match issuer {
+                Some(issuer) => issuer,
+                None => get_map_item("oidc_issuer".to_string())
+                    .map_err(|_| "no oidc issuer in the config, use --issuer")?,
+            };
+            let identity = identity(&issuer, &subject);
+            link_user(&name, &mut ud, &identity).await?;
+            Ok(format!("user {} linked to {}", name, identity))
+        }
+        UserCommands::Invite { count } => Ok(create_invites(count).await?.join("\n")),
+        UserCommands::List => {
+            let mut lines = vec![format!(
+                "{:<24} {:<8} {:<9} {}",
+                "USER", "ROLE", "STATUS", "2FA"
+            )];
+            for (name, ud) in list_users().await? {
+                let status = match (ud.disabled, ud.pending, ud.locked()) {
+                    (true, _, _) => "disabled",
+                    (false, true, _) => "pending",
+                    (false, false, true) => "locked",
+                    (false, false, false) => "active",
+                };
+                let totp = if ud.totp_enabled { "on" } else { "off" };
+                lines.push(format!(
+                    "{:<24} {:<8} {:<9} {}",
+                    name, ud.role, status, totp
+                ));
+            }
+            Ok(lines.join("\n"))
+        }
+    }
+}
+
+async fn existing(name: &str) -> Result<UserData, Box<dyn std::error::Error>> {
+    read_user(name)
+        .await?
+        .ok_or_else(|| format!("no record found for user {}", name).into())
+}
+
+fn check_role(role: &str) -> Result<(), Box<dyn std::error::Error>> {
+    match ROLES.contains(&role) {
+        true => Ok(()),
+        false => Err(format!("role {} is not one of {}", role, ROLES.join(", ")).into()),
+    }
+}
+
+// --password shows up in the process list, without it the password is read from stdin
+fn password_or_prompt(
+    name: &str,
+    password: Option<String>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let password = match password {
+        Some(password) => password,
+        None => {
+            if std::io::stdin().is_terminal() {
+                eprint!("password for {}: ", name);
+                std::io::stderr().flush()?;
+            }
+            let mut line = String::new();
+            std::io::stdin().lock().read_line(&mut line)?;
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    // same rules as registration
+    match password_problem("password", &password) {
+        Some(problem) => Err(problem.into()),
+        None => Ok(password),
+    }
+}
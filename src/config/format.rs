@@ -0,0 +1,240 @@
+use crate::config::process::Parameters;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+/// config file format, taken from the file extension (json without a known one)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl Format {
+    pub fn from_path(path: &str) -> Format {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.to_lowercase());
+        match extension.as_deref() {
+            Some("toml") => Format::Toml,
+            Some("yaml") | Some("yml") => Format::Yaml,
+            // e.g. /etc/ai-webconsole/config
+            _ => Format::Json,
+        }
+    }
+
+    pub fn parse<T: DeserializeOwned>(&self, data: &str) -> Result<T, Box<dyn std::error::Error>> {
+        let value = match self {
+            Format::Json => serde_json::from_str(data)?,
+            Format::Toml => toml::from_str(data)?,
+            Format::Yaml => serde_norway::from_str(data)?,
+        };
+        Ok(value)
+    }
+
+    // toml has no null, unset fields are left out
+    pub fn render(&self, value: &Value) -> Result<String, Box<dyn std::error::Error>> {
+        let data = match self {
+            Format::Json => serde_json::to_string_pretty(value)? + "\n",
+            Format::Toml => toml::to_string_pretty(&without_nulls(value.clone()))?,
+            Format::Yaml => serde_norway::to_string(value)?,
+        };
+        Ok(data)
+    }
+}
+
+/// reads and parses a config file in any of the supported formats
+pub fn load<T: DeserializeOwned>(path: &str) -> Result<T, Box<dyn std::error::Error>> {
+    let format = Format::from_path(path);
+    let data = fs::read_to_string(path).map_err(|e| format!("config {}: {}", path, e))?;
+    let value = format
+        .parse(&data)
+        .map_err(|e| format!("config {}: {}", path, e))?;
+    Ok(value)
+}
+
+/// writes the input config in the format of the output file, comments are not carried over
+pub fn convert(input: &str, output: &str, force: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let value: Value = load(input)?;
+    // catch type errors before writing a file the console would refuse
+    serde_json::from_value::<Parameters>(value.clone())
+        .map_err(|e| format!("config {}: {}", input, e))?;
+    let data = Format::from_path(output).render(&value)?;
+    if !force && Path::new(output).exists() {
+        return Err(format!("{} exists, use --force to overwrite it", output).into());
+    }
+    fs::write(output, data).map_err(|e| format!("{}: {}", output, e))?;
+    Ok(())
+}
+
+fn without_nulls(value: Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k, without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(values) => Value::Array(values.into_iter().map(without_nulls).collect()),
+        value => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+  "name": "console",
+  "port": "9443",
+  "tls": false,
+  "max_connections": 64,
+  "trusted_proxies": ["10.0.0.0/8", "::1"],
+  "listen": [{"address": "[::]:9443", "v6only": true}],
+  "acme": {"directory_url": "https://acme.example.com/directory", "contact": ["ops@example.com"]},
+  "db_path": "/var/lib/console"
+}"#;
+
+    const TOML: &str = r#"# comments are why we want toml
+name = "console"
+port = "9443"
+tls = false
+max_connections = 64
+trusted_proxies = ["10.0.0.0/8", "::1"]
+db_path = "/var/lib/console"
+
+[[listen]]
+address = "[::]:9443"
+v6only = true
+
+[acme]
+directory_url = "https://acme.example.com/directory"
+contact = ["ops@example.com"]
+"#;
+
+    const YAML: &str = r#"# and yaml
+name: console
+port: "9443"
+tls: false
+max_connections: 64
+trusted_proxies:
+  - 10.0.0.0/8
+  - "::1"
+listen:
+  - address: "[::]:9443"
+    v6only: true
+acme:
+  directory_url: https://acme.example.com/directory
+  contact: [ops@example.com]
+db_path: /var/lib/console
+"#;
+
+    fn temp(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("format-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name).to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn format_from_the_extension() {
+        let cases = [
+            ("config.json", Format::Json),
+            ("/etc/console/config.toml", Format::Toml),
+            ("config.yaml", Format::Yaml),
+            ("config.YML", Format::Yaml),
+            // anything else is json
+            ("/etc/ai-webconsole/config", Format::Json),
+            ("config.conf", Format::Json),
+            ("config.toml.bak", Format::Json),
+        ];
+        for (path, format) in cases {
+            assert_eq!(Format::from_path(path), format, "{}", path);
+        }
+    }
+
+    #[test]
+    fn same_config_in_every_format() {
+        let json: Value = Format::Json.parse(JSON).unwrap();
+        let toml: Value = Format::Toml.parse(TOML).unwrap();
+        let yaml: Value = Format::Yaml.parse(YAML).unwrap();
+        assert_eq!(toml, json);
+        assert_eq!(yaml, json);
+        let params: Parameters = Format::Toml.parse(TOML).unwrap();
+        assert_eq!(params.port, "9443");
+        assert_eq!(params.listen.unwrap()[0].v6only, Some(true));
+        assert_eq!(
+            params.acme.unwrap().contact,
+            Some(vec!["ops@example.com".to_string()])
+        );
+    }
+
+    #[test]
+    fn render_round_trips() {
+        let value: Value = Format::Json.parse(JSON).unwrap();
+        for format in [Format::Json, Format::Toml, Format::Yaml] {
+            let data = format.render(&value).unwrap();
+            let back: Value = format.parse(&data).unwrap();
+            assert_eq!(back, value, "{:?}:\n{}", format, data);
+        }
+        assert!(Format::Json.render(&value).unwrap().ends_with("}\n"));
+    }
+
+    #[test]
+    fn toml_leaves_out_nulls() {
+        let value: Value = serde_json::json!({"name": "console", "cert_reload_interval": null,
+            "acme": {"directory_url": "https://acme", "challenge": null}});
+        let data = Format::Toml.render(&value).unwrap();
+        assert!(
+            !data.contains("cert_reload_interval") && !data.contains("challenge"),
+            "{}",
+            data
+        );
+        let back: Value = Format::Toml.parse(&data).unwrap();
+        assert_eq!(back, without_nulls(value));
+    }
+
+    #[test]
+    fn load_errors_name_the_file() {
+        let missing = temp("missing.yaml");
+        let e = load::<Value>(&missing).unwrap_err().to_string();
+        assert!(e.starts_with(&format!("config {}: ", missing)), "{}", e);
+        let broken = temp("broken.toml");
+        fs::write(&broken, "port = ").unwrap();
+        let e = load::<Value>(&broken).unwrap_err().to_string();
+        assert!(e.starts_with(&format!("config {}: ", broken)), "{}", e);
+    }
+
+    #[test]
+    fn convert_between_formats() {
+        let input = temp("convert.json");
+        let output = temp("convert.yaml");
+        let _ = fs::remove_file(&output);
+        fs::write(&input, JSON).unwrap();
+        convert(&input, &output, false).unwrap();
+        assert_eq!(
+            load::<Value>(&output).unwrap(),
+            load::<Value>(&input).unwrap()
+        );
+        assert_eq!(
+            convert(&input, &output, false).unwrap_err().to_string(),
+            format!("{} exists, use --force to overwrite it", output)
+        );
+        convert(&input, &output, true).unwrap();
+
+        // a config the console would refuse isn't converted
+        let bad = temp("bad.json");
+        fs::write(&bad, r#"{"port": 9443}"#).unwrap();
+        let e = convert(&bad, &temp("bad.toml"), false)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            e.starts_with(&format!("config {}: invalid type: integer `9443`", bad)),
+            "{}",
+            e
+        );
+        assert!(!Path::new(&temp("bad.toml")).exists());
+    }
+}
@@ -1 +1,5 @@
+pub mod format;
+pub mod overrides;
 pub mod process;
+pub mod reload;
+pub mod validate;
@@ -0,0 +1,474 @@
+use crate::config::process::{Acme, Oidc, Parameters};
+use custom_logger as log;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+const ENV_PREFIX: &str = "AI_WEBCONSOLE_";
+
+/// every field that can be overridden, acme.directory_url is AI_WEBCONSOLE_ACME_DIRECTORY_URL
+pub const KEYS: &[&str] = &[
+    "name",
+    "description",
+    "port",
+    "listen",
+    "log_level",
+    "certs_dir",
+    "cert_mode",
+    "tls",
+    "trusted_proxies",
+    "redirect_port",
+    "shutdown_timeout",
+    "max_body_size",
+    "handshake_timeout",
+    "header_read_timeout",
+    "keep_alive_timeout",
+    "idle_timeout",
+    "max_connections",
+    "rate_limit",
+    "categories",
+    "config_reload_interval",
+    "admin_users",
+    "registration",
+    "require_2fa",
+    "lockout_attempts",
+    "lockout_account_attempts",
+    "lockout_duration",
+    "cert_sans",
+    "cert_reload_interval",
+    "cert_hosts",
+    "key_passphrase_env",
+    "key_passphrase_file",
+    "acme",
+    "acme.directory_url",
+    "acme.challenge",
+    "acme.http_port",
+    "acme.contact",
+    "acme.ca_bundle",
+    "acme.renew_days",
+    "oidc",
+    "oidc.issuer",
+    "oidc.client_id",
+    "oidc.client_secret",
+    "oidc.redirect_uri",
+    "oidc.scopes",
+    "oidc.username_claim",
+    "oidc.role_claim",
+    "oidc.admin_values",
+    "oidc.create_users",
+    "oidc.ca_bundle",
+    "session_ttl",
+    "client_ca",
+    "client_auth",
+    "db_path",
+    "deploy_dir",
+    "static_dir",
+];
+
+fn env_name(key: &str) -> String {
+    format!("{}{}", ENV_PREFIX, key.replace('.', "_").to_uppercase())
+}
+
+/// applies the AI_WEBCONSOLE_* variables of vars (the process env outside of tests), unknown
+/// ones are logged (they are likely typos)
+pub fn apply_env(
+    params: &mut Parameters,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let vars: HashMap<String, String> = vars
+        .into_iter()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+        .collect();
+    let mut problems = vec![];
+    for key in KEYS {
+        let Some(value) = vars.get(&env_name(key)) else {
+            continue;
+        };
+        if let Err(e) = set(params, key, value) {
+            problems.push(format!("{}: {}", env_name(key), e));
+        }
+    }
+    let known: Vec<String> = KEYS.iter().map(|key| env_name(key)).collect();
+    for name in vars.keys() {
+        // the config file itself and a passphrase variable may share the prefix
+        let passphrase_env = params.key_passphrase_env.as_deref() == Some(name.as_str());
+        if !known.contains(name) && name != "AI_WEBCONSOLE_CONFIG" && !passphrase_env {
+            log::warn!("ignoring unknown config variable {}", name);
+        }
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems.join("\n").into()),
+    }
+}
+
+/// applies the --set key=value flags in order
+pub fn apply_sets(
+    params: &mut Parameters,
+    sets: &[String],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut problems = vec![];
+    for entry in sets {
+        let result = match entry.split_once('=') {
+            Some((key, value)) => set(params, key.trim(), value),
+            None => Err("expected key=value".to_string()),
+        };
+        if let Err(e) = result {
+            problems.push(format!("--set {}: {}", entry, e));
+        }
+    }
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(problems.join("\n").into()),
+    }
+}
+
+// scalars are given as is, lists as "a,b" or json, listen/cert_hosts/acme as json,
+// an empty value unsets an optional field
+fn set(params: &mut Parameters, key: &str, value: &str) -> Result<(), String> {
+    match key {
+        "name" => params.name = value.to_string(),
+        "description" => params.description = value.to_string(),
+        "port" => params.port = value.to_string(),
+        "listen" => params.listen = optional(value, json)?,
+        "log_level" => params.log_level = value.to_string(),
+        "certs_dir" => params.certs_dir = optional(value, string)?,
+        "cert_mode" => params.cert_mode = value.to_string(),
+        "tls" => params.tls = optional(value, parse)?,
+        "trusted_proxies" => params.trusted_proxies = optional(value, list)?,
+        "redirect_port" => params.redirect_port = optional(value, string)?,
+        "shutdown_timeout" => params.shutdown_timeout = optional(value, parse)?,
+        "max_body_size" => params.max_body_size = optional(value, parse)?,
+        "handshake_timeout" => params.handshake_timeout = optional(value, parse)?,
+        "header_read_timeout" => params.header_read_timeout = optional(value, parse)?,
+        "keep_alive_timeout" => params.keep_alive_timeout = optional(value, parse)?,
+        "idle_timeout" => params.idle_timeout = optional(value, parse)?,
+        "max_connections" => params.max_connections = optional(value, parse)?,
+        "rate_limit" => params.rate_limit = optional(value, parse)?,
+        "categories" => params.categories = optional(value, list)?,
+        "config_reload_interval" => params.config_reload_interval = optional(value, parse)?,
+        "admin_users" => params.admin_users = optional(value, list)?,
+        "registration" => params.registration = optional(value, string)?,
+        "require_2fa" => params.require_2fa = optional(value, parse)?,
+        "lockout_attempts" => params.lockout_attempts = optional(value, parse)?,
+        "lockout_account_attempts" => params.lockout_account_attempts = optional(value, parse)?,
+        "lockout_duration" => params.lockout_duration = optional(value, parse)?,
+        "cert_sans" => params.cert_sans = optional(value, list)?,
+        "cert_reload_interval" => params.cert_reload_interval = optional(value, parse)?,
+        "cert_hosts" => params.cert_hosts = optional(value, json)?,
+        "key_passphrase_env" => params.key_passphrase_env = optional(value, string)?,
+        "key_passphrase_file" => params.key_passphrase_file = optional(value, string)?,
+        "acme" => params.acme = optional(value, json)?,
+        "acme.directory_url" => acme(params).directory_url = value.to_string(),
+        "acme.challenge" => acme(params).challenge = optional(value, string)?,
+        "acme.http_port" => acme(params).http_port = optional(value, parse)?,
+        "acme.contact" => acme(params).contact = optional(value, list)?,
+        "acme.ca_bundle" => acme(params).ca_bundle = optional(value, string)?,
+        "acme.renew_days" => acme(params).renew_days = optional(value, parse)?,
+        "oidc" => params.oidc = optional(value, json)?,
+        "oidc.issuer" => oidc(params).issuer = value.to_string(),
+        "oidc.client_id" => oidc(params).client_id = value.to_string(),
+        "oidc.client_secret" => oidc(params).client_secret = optional(value, string)?,
+        "oidc.redirect_uri" => oidc(params).redirect_uri = value.to_string(),
+        "oidc.scopes" => oidc(params).scopes = optional(value, list)?,
+        "oidc.username_claim" => oidc(params).username_claim = optional(value, string)?,
+        "oidc.role_claim" => oidc(params).role_claim = optional(value, string)?,
+        "oidc.admin_values" => oidc(params).admin_values = optional(value, list)?,
+        "oidc.create_users" => oidc(params).create_users = optional(value, parse)?,
+        "oidc.ca_bundle" => oidc(params).ca_bundle = optional(value, string)?,
+        "session_ttl" => params.session_ttl = optional(value, parse)?,
+        "client_ca" => params.client_ca = optional(value, string)?,
+        "client_auth" => params.client_auth = optional(value, string)?,
+        "db_path" => params.db_path = value.to_string(),
+        "deploy_dir" => params.deploy_dir = value.to_string(),
+        "static_dir" => params.static_dir = value.to_string(),
+        _ => return Err(format!("unknown config field {}", key)),
+    }
+    Ok(())
+}
+
+// the acme section is created when one of its fields is set
+fn acme(params: &mut Parameters) -> &mut Acme {
+    params.acme.get_or_insert_with(Acme::default)
+}
+
+fn oidc(params: &mut Parameters) -> &mut Oidc {
+    params.oidc.get_or_insert_with(Oidc::default)
+}
+
+fn optional<T>(value: &str, f: fn(&str) -> Result<T, String>) -> Result<Option<T>, String> {
+    match value.trim().is_empty() {
+        true => Ok(None),
+        false => f(value).map(Some),
+    }
+}
+
+fn string(value: &str) -> Result<String, String> {
+    Ok(value.to_string())
+}
+
+fn parse<T: FromStr>(value: &str) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| format!("{:?} is not valid: {}", value, e))
+}
+
+fn list(value: &str) -> Result<Vec<String>, String> {
+    if value.trim_start().starts_with('[') {
+        return json(value);
+    }
+    Ok(value
+        .split(',')
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+        .collect())
+}
+
+fn json<T: DeserializeOwned>(value: &str) -> Result<T, String> {
+    serde_json::from_str(value).map_err(|e| format!("{:?} is not valid json: {}", value, e))
+}
+
+/// the effective config for --print-config, passwords, secrets and tokens are masked
+/// (the *_env and *_file fields only name where a secret lives)
+pub fn redacted(params: &Parameters) -> Value {
+    let mut value = serde_json::to_value(params).unwrap_or(Value::Null);
+    redact(&mut value);
+    value
+}
+
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                let secret = ["password", "passphrase", "secret", "token"]
+                    .iter()
+                    .any(|word| key.contains(word))
+                    && !key.ends_with("_env")
+                    && !key.ends_with("_file");
+                if secret && !value.is_null() {
+                    *value = Value::String("********".to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::process::layered;
+
+    fn params() -> Parameters {
+        serde_json::from_str("{}").unwrap()
+    }
+
+    fn sets(entries: &[&str]) -> Vec<String> {
+        entries.iter().map(|entry| entry.to_string()).collect()
+    }
+
+    #[test]
+    fn later_sets_win() {
+        let mut params = params();
+        apply_sets(&mut params, &sets(&["port=2222", "port=3333"])).unwrap();
+        assert_eq!(params.port, "3333");
+    }
+
+    #[test]
+    fn set_values_by_kind() {
+        let mut params = params();
+        apply_sets(
+            &mut params,
+            &sets(&[
+                "tls=false",
+                " max_connections = 12",
+                "trusted_proxies=10.0.0.0/8, ::1,",
+                r#"cert_sans=["console.example.com","a,b"]"#,
+                r#"listen=[{"address":"127.0.0.1:8443"},{"unix":"/run/console.sock","mode":"660"}]"#,
+                "acme.challenge=tls-alpn-01",
+                "oidc.admin_values=admins",
+                "description=a=b",
+            ]),
+        )
+        .unwrap();
+        assert_eq!(params.tls, Some(false));
+        assert_eq!(params.max_connections, Some(12));
+        assert_eq!(
+            params.trusted_proxies,
+            Some(vec!["10.0.0.0/8".to_string(), "::1".to_string()])
+        );
+        assert_eq!(
+            params.cert_sans,
+            Some(vec!["console.example.com".to_string(), "a,b".to_string()])
+        );
+        let listen = params.listen.unwrap();
+        assert_eq!(listen[0].address.as_deref(), Some("127.0.0.1:8443"));
+        assert_eq!(listen[1].mode.as_deref(), Some("660"));
+        // a nested field creates its section
+        let acme = params.acme.unwrap();
+        assert_eq!(acme.challenge.as_deref(), Some("tls-alpn-01"));
+        assert_eq!(acme.directory_url, "");
+        let oidc = params.oidc.unwrap();
+        assert_eq!(oidc.admin_values, Some(vec!["admins".to_string()]));
+        // only the first = splits
+        assert_eq!(params.description, "a=b");
+    }
+
+    #[test]
+    fn empty_value_unsets() {
+        let mut params = params();
+        params.cert_reload_interval = Some(60);
+        params.cert_sans = Some(vec!["a".to_string()]);
+        apply_sets(
+            &mut params,
+            &sets(&["cert_reload_interval=", "cert_sans= "]),
+        )
+        .unwrap();
+        assert_eq!(params.cert_reload_interval, None);
+        assert_eq!(params.cert_sans, None);
+    }
+
+    #[test]
+    fn every_bad_set_is_reported() {
+        let mut params = params();
+        let e = apply_sets(
+            &mut params,
+            &sets(&[
+                "max_connections=many",
+                "nope=1",
+                "port",
+                "listen=[",
+                "port=9443",
+            ]),
+        )
+        .unwrap_err();
+        let problems: Vec<String> = e.to_string().lines().map(str::to_string).collect();
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert_eq!(
+            problems[0],
+            "--set max_connections=many: \"many\" is not valid: invalid digit found in string"
+        );
+        assert_eq!(problems[1], "--set nope=1: unknown config field nope");
+        assert_eq!(problems[2], "--set port: expected key=value");
+        assert!(
+            problems[3].starts_with("--set listen=[: \"[\" is not valid json"),
+            "{}",
+            problems[3]
+        );
+        // the good ones are still applied
+        assert_eq!(params.port, "9443");
+    }
+
+    #[test]
+    fn every_key_can_be_set() {
+        for key in KEYS {
+            let mut params = params();
+            // an empty value is accepted by every optional field, the others take it as is
+            let res = set(&mut params, key, "");
+            assert!(res.is_ok(), "{}: {:?}", key, res);
+        }
+        assert_eq!(
+            env_name("acme.directory_url"),
+            "AI_WEBCONSOLE_ACME_DIRECTORY_URL"
+        );
+    }
+
+    fn vars(entries: &[(&str, &str)]) -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn env_vars_set_their_keys() {
+        let mut params = params();
+        apply_env(
+            &mut params,
+            vars(&[
+                ("AI_WEBCONSOLE_PORT", "2222"),
+                ("AI_WEBCONSOLE_ACME_DIRECTORY_URL", "https://acme/dir"),
+                ("AI_WEBCONSOLE_TRUSTED_PROXIES", "10.0.0.0/8,::1"),
+                // not ours, or unknown (only logged)
+                ("PORT", "1"),
+                ("AI_WEBCONSOLE_PROT", "1"),
+                ("AI_WEBCONSOLE_CONFIG", "/etc/ai-webconsole/config.json"),
+            ]),
+        )
+        .unwrap();
+        assert_eq!(params.port, "2222");
+        assert_eq!(params.acme.unwrap().directory_url, "https://acme/dir");
+        assert_eq!(
+            params.trusted_proxies,
+            Some(vec!["10.0.0.0/8".to_string(), "::1".to_string()])
+        );
+    }
+
+    #[test]
+    fn file_then_env_then_set() {
+        let path = std::env::temp_dir().join(format!("overrides-{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"port": "1111", "log_level": "debug", "idle_timeout": 5, "db_path": "/file"}"#,
+        )
+        .unwrap();
+        let config = Some(path.to_string_lossy().to_string());
+        let env = vars(&[
+            ("AI_WEBCONSOLE_PORT", "2222"),
+            ("AI_WEBCONSOLE_LOG_LEVEL", "trace"),
+            ("AI_WEBCONSOLE_DB_PATH", "/env"),
+        ]);
+        let params = layered(config.clone(), env.clone(), &sets(&["port=3333"])).unwrap();
+        // default < file < env < --set
+        assert_eq!(params.name, env!("CARGO_PKG_NAME"));
+        assert_eq!(params.idle_timeout, Some(5));
+        assert_eq!(params.db_path, "/env");
+        assert_eq!(params.log_level, "trace");
+        assert_eq!(params.port, "3333");
+
+        // env and --set problems are reported together
+        let mut env = env;
+        env.extend(vars(&[("AI_WEBCONSOLE_MAX_CONNECTIONS", "many")]));
+        let e = layered(config, env, &sets(&["tls=maybe"]))
+            .unwrap_err()
+            .to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            e,
+            "AI_WEBCONSOLE_MAX_CONNECTIONS: \"many\" is not valid: invalid digit found in string\n\
+             --set tls=maybe: \"maybe\" is not valid: provided string was not `true` or `false`"
+        );
+    }
+
+    #[test]
+    fn secrets_are_redacted() {
+        let mut params = params();
+        apply_sets(
+            &mut params,
+            &sets(&[
+                "oidc.client_secret=s3cret",
+                "key_passphrase_env=CONSOLE_KEY_PASS",
+                "key_passphrase_file=/run/secrets/key",
+            ]),
+        )
+        .unwrap();
+        let value = redacted(&params);
+        assert_eq!(value["oidc"]["client_secret"], "********");
+        let mut secrets = serde_json::json!({"acme": [{"token": "t", "password": null}]});
+        redact(&mut secrets);
+        assert_eq!(secrets["acme"][0]["token"], "********");
+        assert_eq!(secrets["acme"][0]["password"], Value::Null);
+        assert_eq!(value["key_passphrase_env"], "CONSOLE_KEY_PASS");
+        assert_eq!(value["key_passphrase_file"], "/run/secrets/key");
+        // nothing to hide
+        assert_eq!(redacted(&self::params())["key_passphrase_env"], Value::Null);
+    }
+}
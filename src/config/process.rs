@@ -1,30 +1,191 @@
+use crate::config::format::load;
+use crate::config::overrides::{apply_env, apply_sets};
 use serde_derive::{Deserialize, Serialize};
-use std::fs::File;
 
+// fields left out of the config file take these defaults (empty when not set below)
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Parameters {
+    #[serde(default = "default_name")]
     pub name: String,
+    #[serde(default)]
     pub description: String,
+    #[serde(default = "default_port")]
     pub port: String,
+    pub listen: Option<Vec<Listen>>,
+    #[serde(default = "default_log_level")]
     pub log_level: String,
     pub certs_dir: Option<String>,
+    #[serde(default = "default_cert_mode")]
     pub cert_mode: String,
+    // false serves plain http, e.g. behind a tls terminating proxy (default true, https)
+    pub tls: Option<bool>,
+    // addresses or cidr ranges whose Forwarded/X-Forwarded-* headers are honoured
+    pub trusted_proxies: Option<Vec<String>>,
+    // plain http port redirecting to the https console
+    pub redirect_port: Option<String>,
+    // seconds to wait for in-flight requests on SIGTERM/SIGINT (default 30)
+    pub shutdown_timeout: Option<u64>,
+    // request body cap in bytes, larger bodies get a 413 (default 10 MiB)
+    pub max_body_size: Option<u64>,
+    // seconds, defaults: handshake 10, header read 10, keep-alive 60 (0 disables keep-alive), idle 60
+    pub handshake_timeout: Option<u64>,
+    pub header_read_timeout: Option<u64>,
+    pub keep_alive_timeout: Option<u64>,
+    pub idle_timeout: Option<u64>,
+    // concurrent connections over all listeners (default 1024)
+    pub max_connections: Option<usize>,
+    // requests per minute per client, unset or 0 is unlimited
+    pub rate_limit: Option<u32>,
+    // form categories (default generic, stock, projects, programming)
+    pub categories: Option<Vec<String>>,
+    // seconds between config file checks (default 10, 0 only reloads on SIGHUP)
+    pub config_reload_interval: Option<u64>,
+    // client certificate users allowed to use the admin endpoints
+    pub admin_users: Option<Vec<String>>,
+    // self-registration: open (default), invite-code, admin-approval or disabled
+    pub registration: Option<String>,
+    // password logins need a totp code, users without one have to enrol first. client
+    // certificate and openid connect logins are exempt (the certificate is the second factor,
+    // the provider enforces its own mfa)
+    pub require_2fa: Option<bool>,
+    // failed logins before a client ip is locked out of the user (default 5, 0 disables)
+    pub lockout_attempts: Option<u32>,
+    // failed logins from any ip before the whole account is locked (default 20, 0 disables)
+    pub lockout_account_attempts: Option<u32>,
+    // seconds the lockout lasts (default 900)
+    pub lockout_duration: Option<u64>,
+    pub cert_sans: Option<Vec<String>>,
+    pub cert_reload_interval: Option<u64>,
+    pub cert_hosts: Option<Vec<CertHost>>,
+    pub key_passphrase_env: Option<String>,
+    pub key_passphrase_file: Option<String>,
+    pub acme: Option<Acme>,
+    // openid connect login, next to the passwords and client certificates
+    pub oidc: Option<Oidc>,
+    // seconds a login session (oidc) lasts (default 28800)
+    pub session_ttl: Option<u64>,
+    pub client_ca: Option<String>,
+    pub client_auth: Option<String>,
+    #[serde(default)]
     pub db_path: String,
+    #[serde(default)]
     pub deploy_dir: String,
+    #[serde(default)]
     pub static_dir: String,
 }
 
+fn default_name() -> String {
+    env!("CARGO_PKG_NAME").to_string()
+}
+
+fn default_port() -> String {
+    "8443".to_string()
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_cert_mode() -> String {
+    "file".to_string()
+}
+
+// a tcp address ("[::]:8443" is dual-stack unless v6only is set) or a unix socket path
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Listen {
+    pub address: Option<String>,
+    pub v6only: Option<bool>,
+    pub unix: Option<String>,
+    // octal permissions of the unix socket, e.g. "660"
+    pub mode: Option<String>,
+}
+
+// certificate served when the client asks for hostname via SNI
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CertHost {
+    pub hostname: String,
+    pub cert: String,
+    pub key: String,
+}
+
+// used with cert_mode acme, the certificate is issued for cert_sans
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Acme {
+    pub directory_url: String,
+    // http-01 (default) or tls-alpn-01
+    pub challenge: Option<String>,
+    // port for the http-01 challenge listener (default 80)
+    pub http_port: Option<u16>,
+    pub contact: Option<Vec<String>>,
+    // extra ca (pem) trusted for the directory, e.g. pebble.minica.pem
+    pub ca_bundle: Option<String>,
+    // renew when the certificate expires within this many days (default 30)
+    pub renew_days: Option<i64>,
+}
+
+// authorization code flow with pkce against the provider at issuer
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct Oidc {
+    pub issuer: String,
+    pub client_id: String,
+    // AI_WEBCONSOLE_OIDC_CLIENT_SECRET keeps it out of the file, unset for public clients
+    pub client_secret: Option<String>,
+    // the console callback registered with the provider, https://host/webconsole/oidc/callback
+    pub redirect_uri: String,
+    // default openid, profile, email
+    pub scopes: Option<Vec<String>>,
+    // claim with the console user name (default preferred_username)
+    pub username_claim: Option<String>,
+    // claim with the roles or groups (string or list), admin_values give the admin role,
+    // without role_claim the console role is kept
+    pub role_claim: Option<String>,
+    pub admin_values: Option<Vec<String>>,
+    // unknown users are added on their first login (default true)
+    pub create_users: Option<bool>,
+    // extra ca (pem) trusted for the provider
+    pub ca_bundle: Option<String>,
+}
+
 pub trait ConfigInterface {
-    fn read(&self, dir: String) -> Result<Parameters, Box<dyn std::error::Error>>;
+    fn read(
+        &self,
+        name: Option<String>,
+        sets: &[String],
+    ) -> Result<Parameters, Box<dyn std::error::Error>>;
 }
 
 #[derive(Debug, Clone)]
 pub struct ImplConfigInterface {}
 
 impl ConfigInterface for ImplConfigInterface {
-    fn read(&self, name: String) -> Result<Parameters, Box<dyn std::error::Error>> {
-        let json_data = File::open(&name)?;
-        let params = serde_json::from_reader(json_data)?;
-        Ok(params)
+    // layered: defaults < config file < AI_WEBCONSOLE_* env vars < --set key=value
+    fn read(
+        &self,
+        name: Option<String>,
+        sets: &[String],
+    ) -> Result<Parameters, Box<dyn std::error::Error>> {
+        layered(name, std::env::vars(), sets)
+    }
+}
+
+/// the config of the file name with the env vars and --set flags applied on top
+pub fn layered(
+    name: Option<String>,
+    vars: impl IntoIterator<Item = (String, String)>,
+    sets: &[String],
+) -> Result<Parameters, Box<dyn std::error::Error>> {
+    // json, toml or yaml depending on the extension
+    let mut params: Parameters = match &name {
+        Some(name) => load(name)?,
+        None => serde_json::from_str("{}")?,
+    };
+    // report the env and --set problems together
+    let problems: Vec<String> = [apply_env(&mut params, vars), apply_sets(&mut params, sets)]
+        .into_iter()
+        .filter_map(|res| res.err().map(|e| e.to_string()))
+        .collect();
+    match problems.is_empty() {
+        true => Ok(params),
+        false => Err(problems.join("\n").into()),
     }
 }
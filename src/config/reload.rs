@@ -0,0 +1,178 @@
+use crate::MAP_LOOKUP;
+use crate::config::process::{ConfigInterface, ImplConfigInterface, Parameters};
+use crate::config::validate::validate;
+use crate::handlers::ratelimit::set_rate_limit;
+use custom_logger as log;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tokio::signal::unix::{SignalKind, signal};
+
+// applied without a restart, every other change is reported
+const LIVE: &[&str] = &[
+    "log_level",
+    "static_dir",
+    "categories",
+    "rate_limit",
+    "registration",
+    "require_2fa",
+];
+
+pub const DEFAULT_CATEGORIES: &[&str] = &["generic", "stock", "projects", "programming"];
+
+// where the config came from and what is running now
+struct Reload {
+    name: Option<String>,
+    sets: Vec<String>,
+    current: Parameters,
+}
+
+static RELOAD: Mutex<Option<Reload>> = Mutex::new(None);
+
+pub fn level(log_level: &str) -> log::LevelFilter {
+    match log_level {
+        "debug" => log::LevelFilter::Debug,
+        "trace" => log::LevelFilter::Trace,
+        &_ => log::LevelFilter::Info,
+    }
+}
+
+/// remembers how the config was read, reloads read it the same way (file, env, --set)
+pub fn init(name: Option<String>, sets: Vec<String>, params: &Parameters) {
+    set_rate_limit(params.rate_limit.unwrap_or(0));
+    *RELOAD.lock().unwrap() = Some(Reload {
+        name,
+        sets,
+        current: params.clone(),
+    });
+}
+
+pub fn registration(params: &Parameters) -> String {
+    params.registration.clone().unwrap_or("open".to_string())
+}
+
+pub fn categories(params: &Parameters) -> String {
+    match &params.categories {
+        Some(categories) => categories.join(","),
+        None => DEFAULT_CATEGORIES.join(","),
+    }
+}
+
+/// re-reads the config and applies the live settings, an invalid config is refused and
+/// the running one is kept
+pub fn reload() -> Result<String, Box<dyn std::error::Error>> {
+    let mut guard = RELOAD.lock().map_err(|e| e.to_string())?;
+    let state = guard.as_mut().ok_or("config reload is not set up")?;
+    let params = ImplConfigInterface {}.read(state.name.clone(), &state.sets)?;
+    let problems = validate(&params);
+    if !problems.is_empty() {
+        return Err(format!(
+            "config is invalid, keeping the running config:\n  {}",
+            problems.join("\n  ")
+        )
+        .into());
+    }
+    let old = serde_json::to_value(&state.current)?;
+    let new = serde_json::to_value(&params)?;
+    let mut applied = vec![];
+    let mut restart = vec![];
+    for (key, value) in new.as_object().into_iter().flatten() {
+        if old.get(key) == Some(value) {
+            continue;
+        }
+        match LIVE.contains(&key.as_str()) {
+            true => applied.push(key.clone()),
+            false => restart.push(key.clone()),
+        }
+    }
+    let current = &mut state.current;
+    let joined = categories(&params);
+    let mode = registration(&params);
+    if current.log_level != params.log_level {
+        let _ = log::Logging::new()
+            .with_level(level(&params.log_level))
+            .init();
+        current.log_level = params.log_level;
+    }
+    if current.static_dir != params.static_dir || current.categories != params.categories {
+        let mut hm = MAP_LOOKUP.lock().map_err(|e| e.to_string())?;
+        if let Some(hm) = hm.as_mut() {
+            hm.insert("static_dir".to_string(), params.static_dir.clone());
+            hm.insert("categories".to_string(), joined);
+        }
+        current.static_dir = params.static_dir;
+        current.categories = params.categories;
+    }
+    if current.registration != params.registration {
+        let mut hm = MAP_LOOKUP.lock().map_err(|e| e.to_string())?;
+        if let Some(hm) = hm.as_mut() {
+            hm.insert("registration".to_string(), mode);
+        }
+        current.registration = params.registration;
+    }
+    if current.require_2fa != params.require_2fa {
+        let mut hm = MAP_LOOKUP.lock().map_err(|e| e.to_string())?;
+        if let Some(hm) = hm.as_mut() {
+            hm.insert(
+                "require_2fa".to_string(),
+                params.require_2fa.unwrap_or(false).to_string(),
+            );
+        }
+        current.require_2fa = params.require_2fa;
+    }
+    if current.rate_limit != params.rate_limit {
+        set_rate_limit(params.rate_limit.unwrap_or(0));
+        current.rate_limit = params.rate_limit;
+    }
+    let mut summary = vec![];
+    if !applied.is_empty() {
+        summary.push(format!("applied {}", applied.join(", ")));
+    }
+    // the running value stays in place, so these are reported until the restart
+    if !restart.is_empty() {
+        summary.push(format!("restart required for {}", restart.join(", ")));
+    }
+    if summary.is_empty() {
+        summary.push("no changes".to_string());
+    }
+    Ok(summary.join("; "))
+}
+
+fn reload_and_log(reason: &str) {
+    match reload() {
+        Ok(summary) => log::info!("config reload ({}): {}", reason, summary),
+        Err(e) => log::error!("config reload ({}): {}", reason, e),
+    }
+}
+
+/// reloads on SIGHUP or when the config file changes, interval 0 disables polling
+pub async fn watch_config(name: Option<String>, interval: u64) {
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            log::error!("unable to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    let modified = |name: &Option<String>| -> Option<SystemTime> {
+        fs::metadata(name.as_ref()?).ok()?.modified().ok()
+    };
+    let mut last = modified(&name);
+    let poll = interval > 0 && name.is_some();
+    let mut ticker = tokio::time::interval(Duration::from_secs(interval.max(1)));
+    loop {
+        tokio::select! {
+            _ = sighup.recv() => {
+                last = modified(&name);
+                reload_and_log("SIGHUP");
+            }
+            _ = ticker.tick(), if poll => {
+                let now = modified(&name);
+                if now != last {
+                    last = now;
+                    reload_and_log("file changed");
+                }
+            }
+        }
+    }
+}
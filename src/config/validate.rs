@@ -0,0 +1,592 @@
+use crate::config::process::Parameters;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+
+/// checks the parsed config before anything is started, every problem is reported
+/// as "field.path: message"
+pub fn validate(params: &Parameters) -> Vec<String> {
+    let mut problems = Problems(vec![]);
+
+    if params.name.trim().is_empty() {
+        problems.push("name", "must not be empty");
+    }
+    let port = problems.port("port", &params.port);
+    if !matches!(params.log_level.as_str(), "info" | "debug" | "trace") {
+        problems.push(
+            "log_level",
+            format!("{:?} is not one of info, debug, trace", params.log_level),
+        );
+    }
+
+    // listeners
+    for (i, listen) in params.listen.iter().flatten().enumerate() {
+        let path = format!("listen[{}]", i);
+        match (&listen.address, &listen.unix) {
+            (Some(address), None) => {
+                if let Err(e) = address.parse::<SocketAddr>() {
+                    problems.push(
+                        &format!("{}.address", path),
+                        format!(
+                            "{:?} is not an address like 0.0.0.0:8443 or [::]:8443 ({})",
+                            address, e
+                        ),
+                    );
+                }
+            }
+            (None, Some(unix)) => {
+                let parent = Path::new(unix)
+                    .parent()
+                    .filter(|p| !p.as_os_str().is_empty());
+                if let Some(parent) = parent {
+                    problems.dir(&format!("{}.unix", path), &parent.to_string_lossy(), true);
+                }
+                let mode = listen.mode.as_deref().unwrap_or("600");
+                if u32::from_str_radix(mode.trim_start_matches("0o"), 8).is_err() {
+                    problems.push(
+                        &format!("{}.mode", path),
+                        format!("{:?} is not an octal mode like 660", mode),
+                    );
+                }
+            }
+            _ => problems.push(&path, "needs either address or unix"),
+        }
+    }
+    for (i, proxy) in params.trusted_proxies.iter().flatten().enumerate() {
+        let (addr, prefix) = match proxy.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (proxy.as_str(), None),
+        };
+        let valid = match addr.trim().parse::<IpAddr>() {
+            Ok(ip) => match prefix.map(|p| p.parse::<u32>()) {
+                None => true,
+                Some(Ok(p)) => p <= if ip.to_canonical().is_ipv4() { 32 } else { 128 },
+                Some(Err(_)) => false,
+            },
+            Err(_) => false,
+        };
+        if !valid {
+            problems.push(
+                &format!("trusted_proxies[{}]", i),
+                format!("{:?} is not an address or cidr range", proxy),
+            );
+        }
+    }
+    let mut redirect = None;
+    if let Some(redirect_port) = &params.redirect_port {
+        redirect = problems.port("redirect_port", redirect_port);
+        if redirect.is_some() && redirect == port {
+            problems.push("redirect_port", "must differ from port");
+        }
+    }
+
+    // limits, 0 would refuse every request
+    for (field, value) in [
+        ("max_body_size", params.max_body_size),
+        ("handshake_timeout", params.handshake_timeout),
+        ("header_read_timeout", params.header_read_timeout),
+        ("idle_timeout", params.idle_timeout),
+        ("max_connections", params.max_connections.map(|v| v as u64)),
+        ("lockout_duration", params.lockout_duration),
+    ] {
+        if value == Some(0) {
+            problems.push(field, "must be greater than 0");
+        }
+    }
+
+    // categories are kept comma separated in the lookup map
+    if let Some(categories) = &params.categories {
+        if categories.is_empty() {
+            problems.push("categories", "must list at least one category");
+        }
+        for (i, category) in categories.iter().enumerate() {
+            if category.trim().is_empty() || category.contains(',') {
+                problems.push(
+                    &format!("categories[{}]", i),
+                    format!("{:?} must be a non empty name without commas", category),
+                );
+            }
+        }
+    }
+    if params.admin_users.is_some() && params.client_ca.is_none() {
+        problems.push(
+            "admin_users",
+            "admins are client certificate users, set client_ca",
+        );
+    }
+
+    let registration = params.registration.as_deref().unwrap_or("open");
+    if !matches!(
+        registration,
+        "open" | "invite-code" | "admin-approval" | "disabled"
+    ) {
+        problems.push(
+            "registration",
+            format!(
+                "{:?} is not one of open, invite-code, admin-approval, disabled",
+                registration
+            ),
+        );
+    }
+
+    // certificates, not needed when tls is terminated by a proxy
+    if params.tls.unwrap_or(true) {
+        let certs_dir = params.certs_dir.as_deref().unwrap_or_default();
+        match params.cert_mode.as_str() {
+            "file" => {
+                if problems.dir("certs_dir", certs_dir, false) {
+                    problems.file("certs_dir", &format!("{}/ssl.cert", certs_dir));
+                    problems.file("certs_dir", &format!("{}/ssl.key", certs_dir));
+                }
+            }
+            "pkcs12" => {
+                if problems.dir("certs_dir", certs_dir, false) {
+                    problems.file("certs_dir", &format!("{}/ssl.p12", certs_dir));
+                }
+            }
+            // the certificate (and acme account) are written to certs_dir
+            "selfsigned" => {
+                problems.dir("certs_dir", certs_dir, true);
+            }
+            "acme" => {
+                problems.dir("certs_dir", certs_dir, true);
+                if params.cert_sans.as_ref().is_none_or(|sans| sans.is_empty()) {
+                    problems.push("cert_sans", "cert_mode acme needs the domains to issue for");
+                }
+                match &params.acme {
+                    Some(acme) => {
+                        if !acme.directory_url.starts_with("https://")
+                            && !acme.directory_url.starts_with("http://")
+                        {
+                            problems.push(
+                                "acme.directory_url",
+                                format!("{:?} is not an http(s) url", acme.directory_url),
+                            );
+                        }
+                        let challenge = acme.challenge.as_deref().unwrap_or("http-01");
+                        if !matches!(challenge, "http-01" | "tls-alpn-01") {
+                            problems.push(
+                                "acme.challenge",
+                                format!("{:?} is not one of http-01, tls-alpn-01", challenge),
+                            );
+                        }
+                        if acme.http_port == Some(0) {
+                            problems.push("acme.http_port", "must be greater than 0");
+                        }
+                        // the http-01 listener binds its own port (default 80)
+                        let http_port = acme.http_port.unwrap_or(80);
+                        if challenge == "http-01" && Some(http_port) == port {
+                            problems.push(
+                                "acme.http_port",
+                                format!("{} must differ from port", http_port),
+                            );
+                        }
+                        if challenge == "http-01" && Some(http_port) == redirect {
+                            problems.push(
+                                "acme.http_port",
+                                format!("{} must differ from redirect_port", http_port),
+                            );
+                        }
+                        if acme.renew_days.is_some_and(|days| days <= 0) {
+                            problems.push("acme.renew_days", "must be greater than 0");
+                        }
+                        if let Some(ca_bundle) = &acme.ca_bundle {
+                            problems.file("acme.ca_bundle", ca_bundle);
+                        }
+                    }
+                    None => problems.push("acme", "cert_mode acme needs the acme section"),
+                }
+            }
+            mode => problems.push(
+                "cert_mode",
+                format!("{:?} is not one of file, pkcs12, selfsigned, acme", mode),
+            ),
+        }
+        for (i, host) in params.cert_hosts.iter().flatten().enumerate() {
+            if host.hostname.trim().is_empty() {
+                problems.push(&format!("cert_hosts[{}].hostname", i), "must not be empty");
+            }
+            problems.file(&format!("cert_hosts[{}].cert", i), &host.cert);
+            problems.file(&format!("cert_hosts[{}].key", i), &host.key);
+        }
+        if let Some(passphrase_file) = &params.key_passphrase_file {
+            problems.file("key_passphrase_file", passphrase_file);
+        }
+        if let Some(client_ca) = &params.client_ca {
+            problems.file("client_ca", client_ca);
+        }
+        let client_auth = params.client_auth.as_deref().unwrap_or("required");
+        if !matches!(client_auth, "required" | "optional") {
+            problems.push(
+                "client_auth",
+                format!("{:?} is not one of required, optional", client_auth),
+            );
+        }
+    }
+
+    // openid connect
+    if let Some(oidc) = &params.oidc {
+        for (field, url) in [
+            ("oidc.issuer", &oidc.issuer),
+            ("oidc.redirect_uri", &oidc.redirect_uri),
+        ] {
+            if !url.starts_with("https://") && !url.starts_with("http://") {
+                problems.push(field, format!("{:?} is not an http(s) url", url));
+            }
+        }
+        if oidc.client_id.is_empty() {
+            problems.push("oidc.client_id", "must be set");
+        }
+        if !oidc.redirect_uri.ends_with("/oidc/callback") {
+            problems.push(
+                "oidc.redirect_uri",
+                "must point at the console's /webconsole/oidc/callback",
+            );
+        }
+        if oidc.role_claim.is_some() && oidc.admin_values.is_none() {
+            problems.push(
+                "oidc.admin_values",
+                "role_claim needs the claim values that give the admin role",
+            );
+        }
+        if let Some(ca_bundle) = &oidc.ca_bundle {
+            problems.file("oidc.ca_bundle", ca_bundle);
+        }
+    }
+    if params.session_ttl == Some(0) {
+        problems.push("session_ttl", "must be greater than 0");
+    }
+
+    // storage, the trees are created in db_path on the first request
+    problems.dir("db_path", &params.db_path, true);
+    problems.dir("deploy_dir", &params.deploy_dir, false);
+    if problems.dir("static_dir", &params.static_dir, false) {
+        problems.file("static_dir", &format!("{}/index.html", params.static_dir));
+    }
+
+    problems.0
+}
+
+struct Problems(Vec<String>);
+
+impl Problems {
+    fn push(&mut self, path: &str, msg: impl AsRef<str>) {
+        self.0.push(format!("{}: {}", path, msg.as_ref()));
+    }
+
+    fn port(&mut self, path: &str, port: &str) -> Option<u16> {
+        match port.parse::<u16>() {
+            Ok(port) if port > 0 => Some(port),
+            _ => {
+                self.push(path, format!("{:?} is not a port (1-65535)", port));
+                None
+            }
+        }
+    }
+
+    // true when dir is an existing (and if asked writable) directory
+    fn dir(&mut self, path: &str, dir: &str, writable: bool) -> bool {
+        if dir.is_empty() {
+            self.push(path, "must be set");
+            return false;
+        }
+        match fs::metadata(dir) {
+            Ok(meta) if meta.is_dir() => {}
+            Ok(_) => {
+                self.push(path, format!("{} is not a directory", dir));
+                return false;
+            }
+            Err(e) => {
+                self.push(path, format!("{}: {}", dir, e));
+                return false;
+            }
+        }
+        // permission bits don't tell the whole story (root, read-only mounts), try it
+        if writable {
+            let probe = Path::new(dir).join(".ai-webconsole-write-check");
+            match fs::File::create(&probe) {
+                Ok(_) => {
+                    let _ = fs::remove_file(&probe);
+                }
+                Err(e) => {
+                    self.push(path, format!("{} is not writable: {}", dir, e));
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    fn file(&mut self, path: &str, file: &str) {
+        match fs::metadata(file) {
+            Ok(meta) if meta.is_file() => {}
+            Ok(_) => self.push(path, format!("{} is not a file", file)),
+            Err(e) => self.push(path, format!("{}: {}", file, e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::process::{Acme, Listen, Oidc};
+    use std::sync::OnceLock;
+
+    // db_path, deploy_dir and static_dir (with index.html) that pass
+    fn dir() -> &'static str {
+        static DIR: OnceLock<String> = OnceLock::new();
+        DIR.get_or_init(|| {
+            let dir = std::env::temp_dir().join(format!("validate-{}", std::process::id()));
+            fs::create_dir_all(dir.join("static")).unwrap();
+            fs::write(dir.join("static/index.html"), "").unwrap();
+            dir.to_string_lossy().to_string()
+        })
+    }
+
+    fn valid() -> Parameters {
+        let mut params: Parameters = serde_json::from_str("{}").unwrap();
+        params.tls = Some(false);
+        params.db_path = dir().to_string();
+        params.deploy_dir = dir().to_string();
+        params.static_dir = format!("{}/static", dir());
+        params
+    }
+
+    type Change = fn(&mut Parameters);
+
+    fn problems(change: impl FnOnce(&mut Parameters)) -> Vec<String> {
+        let mut params = valid();
+        change(&mut params);
+        validate(&params)
+    }
+
+    #[test]
+    fn valid_config_has_no_problems() {
+        assert_eq!(validate(&valid()), Vec::<String>::new());
+    }
+
+    #[test]
+    fn field_problems() {
+        let cases: Vec<(Change, &str)> = vec![
+            (|p| p.name = " ".to_string(), "name: must not be empty"),
+            (
+                |p| p.port = "0".to_string(),
+                "port: \"0\" is not a port (1-65535)",
+            ),
+            (
+                |p| p.port = "https".to_string(),
+                "port: \"https\" is not a port (1-65535)",
+            ),
+            (
+                |p| p.log_level = "warn".to_string(),
+                "log_level: \"warn\" is not one of info, debug, trace",
+            ),
+            (
+                |p| p.redirect_port = Some("8443".to_string()),
+                "redirect_port: must differ from port",
+            ),
+            (
+                |p| p.trusted_proxies = Some(vec!["10.0.0.0/33".to_string()]),
+                "trusted_proxies[0]: \"10.0.0.0/33\" is not an address or cidr range",
+            ),
+            (
+                |p| p.trusted_proxies = Some(vec!["::1".into(), "proxy".into()]),
+                "trusted_proxies[1]: \"proxy\" is not an address or cidr range",
+            ),
+            (
+                |p| p.idle_timeout = Some(0),
+                "idle_timeout: must be greater than 0",
+            ),
+            (
+                |p| p.max_connections = Some(0),
+                "max_connections: must be greater than 0",
+            ),
+            (
+                |p| p.lockout_duration = Some(0),
+                "lockout_duration: must be greater than 0",
+            ),
+            (
+                |p| p.session_ttl = Some(0),
+                "session_ttl: must be greater than 0",
+            ),
+            (
+                |p| p.categories = Some(vec![]),
+                "categories: must list at least one category",
+            ),
+            (
+                |p| p.categories = Some(vec!["a".into(), "b,c".into()]),
+                "categories[1]: \"b,c\" must be a non empty name without commas",
+            ),
+            (
+                |p| p.admin_users = Some(vec!["alice".into()]),
+                "admin_users: admins are client certificate users, set client_ca",
+            ),
+            (
+                |p| p.registration = Some("closed".to_string()),
+                "registration: \"closed\" is not one of open, invite-code, admin-approval, disabled",
+            ),
+            (|p| p.db_path = String::new(), "db_path: must be set"),
+            (
+                |p| p.deploy_dir = format!("{}/static/index.html", dir()),
+                "is not a directory",
+            ),
+            (|p| p.static_dir = dir().to_string(), "static_dir: "),
+        ];
+        for (change, problem) in cases {
+            let problems = problems(change);
+            assert_eq!(problems.len(), 1, "{:?}", problems);
+            assert!(
+                problems[0].contains(problem),
+                "{:?} for {}",
+                problems,
+                problem
+            );
+        }
+    }
+
+    #[test]
+    fn listen_problems() {
+        let listen = |address: Option<&str>, unix: Option<&str>, mode: Option<&str>| Listen {
+            address: address.map(str::to_string),
+            v6only: None,
+            unix: unix.map(str::to_string),
+            mode: mode.map(str::to_string),
+        };
+        let socket = format!("{}/console.sock", dir());
+        let problems = problems(|p| {
+            p.listen = Some(vec![
+                listen(Some("[::]:8443"), None, None),
+                listen(Some("localhost:8443"), None, None),
+                listen(None, Some(&socket), Some("rw")),
+                listen(Some("0.0.0.0:1"), Some(&socket), None),
+                listen(None, Some("/nonexistent/console.sock"), None),
+            ])
+        });
+        assert_eq!(problems.len(), 4, "{:?}", problems);
+        assert!(problems[0].starts_with(
+            "listen[1].address: \"localhost:8443\" is not an address like 0.0.0.0:8443 or [::]:8443"
+        ));
+        assert_eq!(
+            problems[1],
+            "listen[2].mode: \"rw\" is not an octal mode like 660"
+        );
+        assert_eq!(problems[2], "listen[3]: needs either address or unix");
+        assert!(problems[3].starts_with("listen[4].unix: /nonexistent: "));
+    }
+
+    #[test]
+    fn certificate_problems() {
+        let problems_of = |mode: &str| {
+            problems(|p| {
+                p.tls = None;
+                p.cert_mode = mode.to_string();
+                p.certs_dir = Some(dir().to_string());
+                p.client_auth = Some("sometimes".to_string());
+            })
+        };
+        let file = problems_of("file");
+        assert_eq!(file.len(), 3, "{:?}", file);
+        assert!(file[0].starts_with(&format!("certs_dir: {}/ssl.cert: ", dir())));
+        assert!(file[1].starts_with(&format!("certs_dir: {}/ssl.key: ", dir())));
+        assert_eq!(
+            file[2],
+            "client_auth: \"sometimes\" is not one of required, optional"
+        );
+        assert_eq!(
+            problems_of("letsencrypt")[0],
+            "cert_mode: \"letsencrypt\" is not one of file, pkcs12, selfsigned, acme"
+        );
+        // certificates are not looked at without tls
+        assert!(problems(|p| p.cert_mode = "letsencrypt".to_string()).is_empty());
+    }
+
+    #[test]
+    fn acme_problems() {
+        let acme = |p: &mut Parameters| {
+            p.tls = None;
+            p.cert_mode = "acme".to_string();
+            p.certs_dir = Some(dir().to_string());
+        };
+        assert_eq!(
+            problems(acme),
+            vec![
+                "cert_sans: cert_mode acme needs the domains to issue for",
+                "acme: cert_mode acme needs the acme section",
+            ]
+        );
+        let problems = problems(|p| {
+            acme(p);
+            p.cert_sans = Some(vec!["console.example.com".to_string()]);
+            p.acme = Some(Acme {
+                directory_url: "ftp://acme".to_string(),
+                challenge: Some("dns-01".to_string()),
+                http_port: Some(0),
+                renew_days: Some(0),
+                ..Default::default()
+            });
+        });
+        assert_eq!(
+            problems,
+            vec![
+                "acme.directory_url: \"ftp://acme\" is not an http(s) url",
+                "acme.challenge: \"dns-01\" is not one of http-01, tls-alpn-01",
+                "acme.http_port: must be greater than 0",
+                "acme.renew_days: must be greater than 0",
+            ]
+        );
+        let clash = |http_port: Option<u16>, challenge: &str| {
+            let mut params = valid();
+            acme(&mut params);
+            params.cert_sans = Some(vec!["console.example.com".to_string()]);
+            params.acme = Some(Acme {
+                directory_url: "https://acme".to_string(),
+                challenge: Some(challenge.to_string()),
+                http_port,
+                ..Default::default()
+            });
+            validate(&params)
+        };
+        assert_eq!(
+            clash(Some(8443), "http-01"),
+            vec!["acme.http_port: 8443 must differ from port"]
+        );
+        assert!(clash(Some(8080), "http-01").is_empty());
+        // the redirect listener usually has port 80, the default http_port
+        let mut params = valid();
+        acme(&mut params);
+        params.cert_sans = Some(vec!["console.example.com".to_string()]);
+        params.redirect_port = Some("80".to_string());
+        params.acme = Some(Acme {
+            directory_url: "https://acme".to_string(),
+            ..Default::default()
+        });
+        assert_eq!(
+            validate(&params),
+            vec!["acme.http_port: 80 must differ from redirect_port"]
+        );
+        assert!(clash(None, "http-01").is_empty());
+        // tls-alpn-01 is answered on the console port
+        assert!(clash(Some(8443), "tls-alpn-01").is_empty());
+    }
+
+    #[test]
+    fn oidc_problems() {
+        let problems = problems(|p| {
+            p.oidc = Some(Oidc {
+                issuer: "sso.example.com".to_string(),
+                redirect_uri: "https://console/callback".to_string(),
+                role_claim: Some("groups".to_string()),
+                ..Default::default()
+            })
+        });
+        assert_eq!(
+            problems,
+            vec![
+                "oidc.issuer: \"sso.example.com\" is not an http(s) url",
+                "oidc.client_id: must be set",
+                "oidc.redirect_uri: must point at the console's /webconsole/oidc/callback",
+                "oidc.admin_values: role_claim needs the claim values that give the admin role",
+            ]
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use crate::handlers::common::{get_error, get_opts};
+use crate::handlers::common::{get_error, get_map_item, get_opts};
 use crate::handlers::interface::InputformInterface;
 use async_trait::async_trait;
 use chrono::Local;
@@ -114,7 +114,7 @@ async fn db_upsert(id: String, fd: FormData) -> Result<String, Box<dyn std::erro
     Ok(msg)
 }
 
-async fn db_read(id: String, db: String) -> Result<FormData, Box<dyn std::error::Error>> {
+pub async fn db_read(id: String, db: String) -> Result<FormData, Box<dyn std::error::Error>> {
     let tree = get_opts(db)?;
     // start transaction
     let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
@@ -208,9 +208,9 @@ fn render_results_html(rows: HashMap<String, FormData>) -> String {
             <td>{}</td>
             <td>{}</td>
             <td>{}</td>
-            <td><i id=\"icon-delete\" class=\"fa fa-trash-o\" hx-delete=\"/webconsole/formdata/{}/{}\" hx-trigger=\"click\" hx-target=\"#response\"></i>&nbsp&nbsp;&nbsp;<i id=\"icon-formdata\" class=\"fa fa-edit\" hx-get=\"/webconsole/formdata/{}/{}\" hx-target=\"#inputForm\" hx-trigger=\"click\"></i></td>
+            <td><i id=\"icon-delete\" class=\"fa fa-trash-o\" hx-delete=\"/webconsole/formdata/{}/{}\" hx-trigger=\"click\" hx-target=\"#response\"></i>&nbsp&nbsp;&nbsp;<i id=\"icon-formdata\" class=\"fa fa-edit\" hx-get=\"/webconsole/formdata/{}/{}\" hx-target=\"#inputForm\" hx-trigger=\"click\"></i>&nbsp;&nbsp;&nbsp;<i id=\"icon-outputs\" class=\"fa fa-file-text-o\" hx-post=\"/webconsole/documents\" hx-ext=\"json-enc\" hx-vals='{{\"source\": \"{}\"}}' hx-target=\"#document-results\" hx-trigger=\"click\"></i></td>
         </tr>",
-            key, fd.title, fd.category, fd.file, fd.prompt, key, fd.db, key,fd.db
+            key, fd.title, fd.category, fd.file, fd.prompt, key, fd.db, key,fd.db, key
         );
         html.push_str(&html_row);
     }
@@ -258,7 +258,7 @@ fn render_form_html(key: String, fd: FormData) -> String {
         fd.credentials,
         fd.db,
         fd.title,
-        check_selected(fd.category),
+        category_options(&fd.category),
         fd.file,
         fd.prompt,
         checked
@@ -266,34 +266,24 @@ fn render_form_html(key: String, fd: FormData) -> String {
     html
 }
 
-fn check_selected(category: String) -> String {
-    let mut vec_selected = vec!["", "", "", ""];
-    match category.as_str() {
-        "generic" => {
-            vec_selected[0] = "selected";
-        }
-        "stock" => {
-            vec_selected[1] = "selected";
-        }
-        "projects" => {
-            vec_selected[2] = "selected";
-        }
-        "programming" => {
-            vec_selected[3] = "selected";
-        }
-        _ => {
-            vec_selected[0] = "selected";
-        }
+// the categories come from the config (reloaded live), unknown values select the first one
+pub fn category_options(category: &str) -> String {
+    let categories = get_map_item("categories".to_string()).unwrap_or_default();
+    let categories: Vec<&str> = categories.split(',').filter(|c| !c.is_empty()).collect();
+    let selected = match categories.contains(&category) {
+        true => category,
+        false => categories.first().copied().unwrap_or_default(),
     };
-
-    let result = format!(
-        r#"
-    <option id="generic" name="generic" {}>generic</option>
-    <option id="stock" name="stock" {}>stock</option>
-    <option id="projects" name="projects" {}>projects</option>
-    <option id="programming" name="programming" {}>programming</option>
-    "#,
-        vec_selected[0], vec_selected[1], vec_selected[2], vec_selected[3]
-    );
+    let mut result = String::new();
+    for name in categories {
+        let name_html = ammonia::clean_text(name);
+        result.push_str(&format!(
+            "\n    <option id=\"{}\" name=\"{}\" {}>{}</option>",
+            name_html,
+            name_html,
+            if name == selected { "selected" } else { "" },
+            name_html
+        ));
+    }
     result
 }
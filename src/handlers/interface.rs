@@ -1,3 +1,4 @@
+use crate::handlers::view::Export;
 use async_trait::async_trait;
 use hyper::body::Bytes;
 
@@ -18,12 +19,36 @@ pub trait InputformInterface {
 #[async_trait]
 pub trait LoginformInterface {
     async fn save_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>>;
-    async fn get_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>>;
+    async fn get_formdata(data: Bytes, ip: String) -> Result<String, Box<dyn std::error::Error>>;
+    async fn password_formdata(
+        data: Bytes,
+        ip: String,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+    async fn reset_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>>;
+    async fn totp_formdata(
+        req_uri: String,
+        data: Bytes,
+        ip: String,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+    async fn cert_formdata(user: String) -> Result<String, Box<dyn std::error::Error>>;
 }
 
 #[allow(dead_code)]
 #[async_trait]
 pub trait ViewformInterface {
     async fn get_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>>;
+    async fn render_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>>;
     async fn save_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>>;
+    async fn search_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>>;
+    async fn delete_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>>;
+    async fn rename_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>>;
+    async fn history_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>>;
+    async fn revision_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>>;
+    async fn diff_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>>;
+    async fn restore_formdata(
+        req_uri: String,
+        data: Bytes,
+    ) -> Result<String, Box<dyn std::error::Error>>;
+    async fn export_formdata(req_uri: String) -> Result<Export, Box<dyn std::error::Error>>;
+    async fn bundle_formdata(req_uri: String) -> Result<Export, Box<dyn std::error::Error>>;
 }
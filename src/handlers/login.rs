@@ -1,14 +1,293 @@
-use crate::handlers::common::{get_error, get_opts};
+use crate::handlers::common::{get_error, get_map_item, get_opts};
 use crate::handlers::interface::LoginformInterface;
+use crate::handlers::request::{
+    LoginRequest, PasswordRequest, RegisterRequest, ResetRequest, decode, filled,
+};
+use crate::handlers::totp;
 use async_trait::async_trait;
+use aws_lc_rs::constant_time::verify_slices_are_equal;
+use aws_lc_rs::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Local;
 use custom_logger as log;
+use http::StatusCode;
 use hyper::body::Bytes;
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use std::num::NonZeroU32;
+use tokio::sync::{Mutex, MutexGuard};
+
+// failures, totp steps, recovery codes and passwords are read-modify-writes of the user record,
+// they are serialized so concurrent logins can't lose a failure or use a code twice
+pub static LOGIN_WRITE: Mutex<()> = Mutex::const_new(());
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UserData {
     pub password: String,
     pub session_id: String,
+    // "user" or "admin", records written before roles existed are users
+    #[serde(default = "default_role")]
+    pub role: String,
+    #[serde(default)]
+    pub disabled: bool,
+    // registered in admin-approval mode, can't log in until approved
+    #[serde(default)]
+    pub pending: bool,
+    // sha256 of the single use token from `user reset`, valid until reset_expires (unix time)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reset_token: Option<String>,
+    #[serde(default)]
+    pub reset_expires: i64,
+    // failed password logins per client ip
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub failures: HashMap<String, Failures>,
+    // failed logins from any ip, rotating the client address doesn't get around the lock
+    #[serde(default)]
+    pub account_failures: Failures,
+    // base32 totp secret, asked for at login once totp_enabled (the enrolment is confirmed)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    // last time step used, a code is only accepted once
+    #[serde(default)]
+    pub totp_last: i64,
+    // sha256 of the unused recovery codes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovery_codes: Vec<String>,
+    // openid connect issuer and subject the user is linked to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc_identity: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Failures {
+    pub count: u32,
+    // the ip (or anyone for the account) can't log in as the user until then (unix time)
+    pub locked_until: i64,
+    // the count is forgotten lockout_duration after the last failure
+    #[serde(default)]
+    pub last: i64,
+}
+
+// client ips with failures kept per user, the oldest are dropped beyond it
+const MAX_FAILURE_IPS: usize = 100;
+
+impl Failures {
+    // counts one more failure, true when it reaches attempts (0 never locks) and locks
+    fn add(&mut self, attempts: u32, duration: i64, now: i64) -> bool {
+        if self.last + duration <= now {
+            self.count = 0;
+        }
+        self.count += 1;
+        self.last = now;
+        if attempts == 0 || self.count < attempts {
+            return false;
+        }
+        self.count = 0;
+        self.locked_until = now + duration;
+        true
+    }
+}
+
+impl UserData {
+    pub fn new(password: String, role: String, pending: bool) -> Self {
+        UserData {
+            password,
+            session_id: "123456".to_string(),
+            role,
+            disabled: false,
+            pending,
+            reset_token: None,
+            reset_expires: 0,
+            failures: HashMap::new(),
+            account_failures: Failures::default(),
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last: 0,
+            recovery_codes: vec![],
+            oidc_identity: None,
+        }
+    }
+
+    // counts a failed password or totp code, lockout_attempts failures lock the ip out and
+    // lockout_account_attempts failures from any ip lock the account
+    fn add_failure(&mut self, id: &str, ip: &str) {
+        let (attempts, account_attempts, duration) = lockout();
+        let now = Local::now().timestamp();
+        if self
+            .failures
+            .entry(ip.to_string())
+            .or_default()
+            .add(attempts, duration, now)
+        {
+            log::warn!(
+                "user {} locked for {} after {} failed logins",
+                id,
+                ip,
+                attempts
+            );
+        }
+        prune_failures(&mut self.failures, duration, now);
+        if self.account_failures.add(account_attempts, duration, now) {
+            log::warn!(
+                "user {} locked for every ip after {} failed logins",
+                id,
+                account_attempts
+            );
+        }
+    }
+
+    // a successful login forgets the failures of the ip and the account count (not the locks)
+    fn clear_failures(&mut self, ip: &str) -> bool {
+        let cleared = self.failures.remove(ip).is_some() || self.account_failures.count > 0;
+        self.account_failures.count = 0;
+        cleared
+    }
+
+    /// lifts every lock (admin unlock and reset)
+    pub fn unlock(&mut self) {
+        self.failures.clear();
+        self.account_failures = Failures::default();
+    }
+
+    /// true while the account or any client ip is locked out
+    pub fn locked(&self) -> bool {
+        let now = Local::now().timestamp();
+        self.account_failures.locked_until > now
+            || self.failures.values().any(|f| f.locked_until > now)
+    }
+}
+
+pub fn token_hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+// passwords are stored as pbkdf2-sha256$iterations$salt$hash, records written before that
+// hold the plain password until the next login that matches it
+const PASSWORD_SCHEME: &str = "pbkdf2-sha256";
+const PASSWORD_ITERATIONS: u32 = 600_000;
+
+pub fn hash_password(password: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut salt = [0u8; 16];
+    aws_lc_rs::rand::fill(&mut salt).map_err(|_| "unable to generate a password salt")?;
+    let iterations = NonZeroU32::new(PASSWORD_ITERATIONS).ok_or("no password iterations")?;
+    let mut hash = [0u8; 32];
+    pbkdf2::derive(
+        PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    Ok(format!(
+        "{}${}${}${}",
+        PASSWORD_SCHEME,
+        iterations,
+        URL_SAFE_NO_PAD.encode(salt),
+        URL_SAFE_NO_PAD.encode(hash)
+    ))
+}
+
+fn password_hashed(stored: &str) -> bool {
+    stored.starts_with(&format!("{}$", PASSWORD_SCHEME))
+}
+
+fn password_matches(stored: &str, password: &str) -> bool {
+    if !password_hashed(stored) {
+        return verify_slices_are_equal(stored.as_bytes(), password.as_bytes()).is_ok();
+    }
+    let parts: Vec<&str> = stored.split('$').collect();
+    let [_, iterations, salt, hash] = parts[..] else {
+        return false;
+    };
+    let iterations = iterations.parse().ok().and_then(NonZeroU32::new);
+    let (Some(iterations), Ok(salt), Ok(hash)) = (
+        iterations,
+        URL_SAFE_NO_PAD.decode(salt),
+        URL_SAFE_NO_PAD.decode(hash),
+    ) else {
+        return false;
+    };
+    hash.len() == 32
+        && pbkdf2::verify(
+            PBKDF2_HMAC_SHA256,
+            iterations,
+            &salt,
+            password.as_bytes(),
+            &hash,
+        )
+        .is_ok()
+}
+
+// require_2fa refuses password logins of users without totp, certificate and openid connect
+// logins don't come through here
+fn require_2fa() -> bool {
+    get_map_item("require_2fa".to_string())
+        .map(|v| v == "true")
+        .unwrap_or(false)
+}
+
+// ips are forgotten once their lock and last failure are older than the lockout, beyond
+// MAX_FAILURE_IPS the least recent are dropped
+fn prune_failures(failures: &mut HashMap<String, Failures>, duration: i64, now: i64) {
+    failures.retain(|_, f| f.locked_until > now || f.last + duration > now);
+    while failures.len() > MAX_FAILURE_IPS {
+        let oldest = failures
+            .iter()
+            .min_by_key(|(_, f)| f.last.max(f.locked_until))
+            .map(|(ip, _)| ip.clone());
+        let Some(oldest) = oldest else {
+            break;
+        };
+        failures.remove(&oldest);
+    }
+}
+
+// lockout_attempts failures (default 5, 0 disables) lock the ip and lockout_account_attempts
+// (default 20, 0 disables) the whole account for lockout_duration seconds (default 900)
+fn lockout() -> (u32, u32, i64) {
+    let attempts = get_map_item("lockout_attempts".to_string())
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(5);
+    let account_attempts = get_map_item("lockout_account_attempts".to_string())
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(20);
+    let duration = get_map_item("lockout_duration".to_string())
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(900);
+    (attempts, account_attempts, duration)
+}
+
+/// a refused registration, answered with its status instead of a 500
+#[derive(Debug)]
+pub struct Refused {
+    pub status: StatusCode,
+    pub msg: String,
+}
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+pub fn refused(status: StatusCode, msg: String) -> Box<dyn std::error::Error> {
+    Box::new(Refused { status, msg })
+}
+
+pub const ROLES: &[&str] = &["user", "admin"];
+
+fn default_role() -> String {
+    "user".to_string()
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -16,46 +295,260 @@ pub struct User {}
 
 #[async_trait]
 impl LoginformInterface for User {
-    async fn get_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>> {
-        let value = String::from_utf8(data.to_vec())?;
-        let (user_res, password_res) = value.split_once("&").ok_or("could not parse parameters")?;
-        let user = user_res.split("=").last().ok_or("could not parse user")?;
-        let password = password_res
-            .split("=")
-            .last()
-            .ok_or("could not parse password")?;
-        let result = db_read(user.to_string(), password.to_string()).await?;
+    // users with totp post the code (or a recovery code) with the password, without it
+    // the answer is a 401 asking for the code
+    async fn get_formdata(data: Bytes, ip: String) -> Result<String, Box<dyn std::error::Error>> {
+        let req: LoginRequest = decode(&data)?;
+        req.validate()?;
+        let (user, password) = (req.username.as_str(), req.password.as_str());
+        let code = filled(&req.code);
+        let result = db_read(user.to_string(), password.to_string(), ip, code).await?;
         Ok(result)
     }
 
+    // totp/enroll creates the secret, totp/confirm turns it on with a first code and
+    // returns the recovery codes, totp/disable turns it off (password and code needed)
+    async fn totp_formdata(
+        req_uri: String,
+        data: Bytes,
+        ip: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let req: LoginRequest = decode(&data)?;
+        req.validate()?;
+        let (user, password) = (req.username.as_str(), req.password.as_str());
+        let code = filled(&req.code);
+        let (_write, mut ud) = verify(user, password, &ip).await?;
+        if ud.disabled || ud.pending {
+            return Err(get_error(format!("user {} can't log in", user)));
+        }
+        let action = req_uri.split("?").next().unwrap_or_default();
+        if action == "/webconsole/totp/enroll" {
+            if ud.totp_enabled {
+                return Err(refused(
+                    StatusCode::CONFLICT,
+                    format!("two-factor authentication is already on for user {}", user),
+                ));
+            }
+            let secret = totp::generate_secret()?;
+            let issuer =
+                get_map_item("name".to_string()).unwrap_or(env!("CARGO_PKG_NAME").to_string());
+            let uri = totp::provisioning_uri(&issuer, user, &secret);
+            ud.totp_secret = Some(secret.clone());
+            write_user(user, &ud).await?;
+            log::info!("user {} started the totp enrolment", user);
+            return Ok(format!(
+                "add this to the authenticator app (scan the uri as a qr code or enter the secret), then confirm with a code\nsecret: {}\nuri: {}",
+                secret, uri
+            ));
+        }
+        if action == "/webconsole/totp/confirm" {
+            let Some(secret) = ud.totp_secret.clone().filter(|_| !ud.totp_enabled) else {
+                return Err(get_error(format!(
+                    "no totp enrolment started for user {}",
+                    user
+                )));
+            };
+            let now = Local::now().timestamp();
+            let Some(step) = code.and_then(|code| totp::verify(&secret, code, now, 0)) else {
+                return Err(refused(
+                    StatusCode::UNAUTHORIZED,
+                    "incorrect totp code".to_string(),
+                ));
+            };
+            let codes = totp::recovery_codes(10)?;
+            ud.totp_enabled = true;
+            ud.totp_last = step;
+            ud.recovery_codes = codes
+                .iter()
+                .map(|c| token_hash(&totp::normalize(c)))
+                .collect();
+            write_user(user, &ud).await?;
+            log::info!("user {} turned on two-factor authentication", user);
+            return Ok(format!(
+                "two-factor authentication is on, keep these recovery codes (each works once)\n{}",
+                codes.join("\n")
+            ));
+        }
+        if action == "/webconsole/totp/disable" {
+            if !ud.totp_enabled {
+                return Err(get_error(format!(
+                    "two-factor authentication is off for user {}",
+                    user
+                )));
+            }
+            if require_2fa() {
+                return Err(refused(
+                    StatusCode::FORBIDDEN,
+                    "two-factor authentication is required".to_string(),
+                ));
+            }
+            second_factor(user, &mut ud, code, &ip).await?;
+            ud.totp_secret = None;
+            ud.totp_enabled = false;
+            ud.recovery_codes.clear();
+            write_user(user, &ud).await?;
+            log::info!("user {} turned off two-factor authentication", user);
+            return Ok(format!(
+                "two-factor authentication is off for user {}",
+                user
+            ));
+        }
+        Err(get_error(format!("unknown totp request {}", req_uri)))
+    }
+
+    // the current password (and totp code) is checked like a login, failures count towards the lockout
+    async fn password_formdata(
+        data: Bytes,
+        ip: String,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let req: PasswordRequest = decode(&data)?;
+        req.validate()?;
+        let (user, password) = (req.username.as_str(), req.password.as_str());
+        let new_password = hash_password(&req.new_password)?;
+        let (_write, mut ud) = verify(user, password, &ip).await?;
+        if ud.disabled {
+            return Err(get_error(format!("user {} is disabled", user)));
+        }
+        if ud.totp_enabled {
+            second_factor(user, &mut ud, filled(&req.code), &ip).await?;
+        }
+        ud.password = new_password;
+        write_user(user, &ud).await?;
+        log::info!("user {} changed the password", user);
+        Ok(format!("password of user {} changed", user))
+    }
+
+    // a reset token (from `user reset`) replaces the current password once
+    async fn reset_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>> {
+        let req: ResetRequest = decode(&data)?;
+        req.validate()?;
+        let (user, token) = (req.username.as_str(), req.token.as_str());
+        let invalid = || {
+            refused(
+                StatusCode::FORBIDDEN,
+                "the reset token is invalid or expired".to_string(),
+            )
+        };
+        let new_password = hash_password(&req.new_password)?;
+        let _write = LOGIN_WRITE.lock().await;
+        let mut ud = read_user(user).await?.ok_or_else(invalid)?;
+        let valid = ud.reset_token.as_deref() == Some(token_hash(token).as_str())
+            && ud.reset_expires > Local::now().timestamp();
+        if !valid {
+            log::warn!("password reset for user {} refused", user);
+            return Err(invalid());
+        }
+        // the admin reset also lifts the lockouts
+        ud.password = new_password;
+        ud.reset_token = None;
+        ud.reset_expires = 0;
+        ud.unlock();
+        write_user(user, &ud).await?;
+        log::info!("user {} reset the password", user);
+        Ok(format!("password of user {} reset", user))
+    }
+
+    // registration follows the registration mode: open, invite-code, admin-approval or disabled
     async fn save_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>> {
-        let value = String::from_utf8(data.to_vec())?;
-        let (user_res, password_res) = value.split_once("&").ok_or("could not parse parameters")?;
-        let user = user_res.split("=").last().ok_or("could not parse user")?;
-        let password = password_res
-            .split("=")
-            .last()
-            .ok_or("could not parse password")?;
-        let result =
-            db_upsert(user.to_string(), password.to_string(), "123456".to_string()).await?;
+        let mode = get_map_item("registration".to_string()).unwrap_or("open".to_string());
+        if mode == "disabled" {
+            return Err(refused(
+                StatusCode::FORBIDDEN,
+                "registration is disabled".to_string(),
+            ));
+        }
+        let req: RegisterRequest = decode(&data)?;
+        req.validate()?;
+        let invite = match mode.as_str() {
+            "invite-code" => Some(filled(&req.invite).ok_or_else(|| {
+                refused(
+                    StatusCode::FORBIDDEN,
+                    "an invite code is required to register".to_string(),
+                )
+            })?),
+            _ => None,
+        };
+        let result = db_upsert(
+            req.username.clone(),
+            req.password.clone(),
+            "123456".to_string(),
+            invite,
+            mode == "admin-approval",
+        )
+        .await?;
         Ok(result)
     }
+
+    // the client certificate was verified during the handshake, only the user has to exist
+    async fn cert_formdata(user: String) -> Result<String, Box<dyn std::error::Error>> {
+        let tree = get_opts("login".to_string())?;
+        let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+        let key = Bytes::from(user.clone());
+        let res = txn.get(&key).map_err(|e| get_error(e.to_string()))?;
+        txn.commit().await?;
+        tree.close().await?;
+        match res {
+            Some(val) => {
+                let ud: UserData =
+                    serde_json::from_slice(&val).map_err(|e| get_error(e.to_string()))?;
+                if ud.disabled {
+                    return Err(get_error(format!("user {} is disabled", user)));
+                }
+                if ud.pending {
+                    return Err(get_error(format!(
+                        "user {} is waiting for admin approval",
+                        user
+                    )));
+                }
+                log::info!("user {} logged in with client certificate", user);
+                Ok("login successful".to_string())
+            }
+            None => {
+                let msg = format!("no record found for certificate user {}", user);
+                log::error!("{}", msg);
+                Err(get_error(msg))
+            }
+        }
+    }
 }
 
+// adds a new user, an existing user is never overwritten (409)
 async fn db_upsert(
     id: String,
     password: String,
     session_id: String,
+    invite: Option<&str>,
+    pending: bool,
 ) -> Result<String, Box<dyn std::error::Error>> {
+    let password = hash_password(&password)?;
     let tree = get_opts("login".to_string())?;
     // start transaction
     let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
     log::debug!("transaction begin");
     let key = Bytes::from(id.clone());
-    let ud = UserData {
-        password,
-        session_id,
+    let existing = txn.get(&key).map_err(|e| get_error(e.to_string()))?;
+    if existing.is_some() {
+        tree.close().await?;
+        log::warn!("registration refused, user {} exists", id);
+        return Err(refused(
+            StatusCode::CONFLICT,
+            format!("user {} already exists", id),
+        ));
+    }
+    // the code is only used up once the name is known to be free
+    let invited = match invite {
+        Some(code) => use_invite(code).await?,
+        None => true,
     };
+    if !invited {
+        tree.close().await?;
+        return Err(refused(
+            StatusCode::FORBIDDEN,
+            "the invite code is invalid or was used".to_string(),
+        ));
+    }
+    let mut ud = UserData::new(password, default_role(), pending);
+    ud.session_id = session_id;
     let json_data = serde_json::to_string(&ud)?;
     let value = Bytes::from(json_data);
     txn.set(&key, &value)
@@ -63,32 +556,356 @@ async fn db_upsert(
     // commit transaction
     txn.commit().await?;
     tree.close().await?;
-    let msg = format!("user {} registered successfully", id);
+    let msg = match pending {
+        true => format!("user {} registered, waiting for admin approval", id),
+        false => format!("user {} registered successfully", id),
+    };
     Ok(msg)
 }
 
-async fn db_read(id: String, password: String) -> Result<String, Box<dyn std::error::Error>> {
+/// creates single use invite codes for the invite-code registration mode
+pub async fn create_invites(count: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let tree = get_opts("invites".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let mut codes = vec![];
+    for _ in 0..count {
+        let mut bytes = [0u8; 12];
+        aws_lc_rs::rand::fill(&mut bytes).map_err(|_| "unable to generate an invite code")?;
+        let code = URL_SAFE_NO_PAD.encode(bytes);
+        let created = Local::now().to_rfc3339();
+        txn.set(code.as_bytes(), created.as_bytes())
+            .map_err(|e| get_error(e.to_string()))?;
+        codes.push(code);
+    }
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(codes)
+}
+
+// removes the code, false when it doesn't exist (never created or already used)
+async fn use_invite(code: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let tree = get_opts("invites".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let found = txn
+        .get(code.as_bytes())
+        .map_err(|e| get_error(e.to_string()))?
+        .is_some();
+    if found {
+        txn.delete(code.as_bytes())
+            .map_err(|e| get_error(e.to_string()))?;
+    }
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(found)
+}
+
+async fn db_read(
+    id: String,
+    password: String,
+    ip: String,
+    code: Option<&str>,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let (_write, mut ud) = verify(&id, &password, &ip).await?;
+    if ud.disabled {
+        return Err(get_error(format!("user {} is disabled", id)));
+    }
+    if ud.pending {
+        return Err(get_error(format!(
+            "user {} is waiting for admin approval",
+            id
+        )));
+    }
+    if !ud.totp_enabled {
+        if require_2fa() {
+            return Err(refused(
+                StatusCode::FORBIDDEN,
+                "two-factor authentication is required, enrol on /webconsole/totp/enroll first"
+                    .to_string(),
+            ));
+        }
+        return Ok("login successful".to_string());
+    }
+    second_factor(&id, &mut ud, code, &ip).await?;
+    Ok("login successful".to_string())
+}
+
+// checks the totp code or uses up a recovery code, a wrong code counts as a failed login
+async fn second_factor(
+    id: &str,
+    ud: &mut UserData,
+    code: Option<&str>,
+    ip: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(code) = code else {
+        return Err(refused(
+            StatusCode::UNAUTHORIZED,
+            "totp code required".to_string(),
+        ));
+    };
+    let secret = ud.totp_secret.clone().unwrap_or_default();
+    let now = Local::now().timestamp();
+    if let Some(step) = totp::verify(&secret, code, now, ud.totp_last) {
+        ud.totp_last = step;
+        ud.clear_failures(ip);
+        write_user(id, ud).await?;
+        return Ok(());
+    }
+    let hash = token_hash(&totp::normalize(code));
+    if let Some(pos) = ud.recovery_codes.iter().position(|c| *c == hash) {
+        ud.recovery_codes.remove(pos);
+        ud.clear_failures(ip);
+        write_user(id, ud).await?;
+        log::warn!(
+            "user {} used a recovery code, {} left",
+            id,
+            ud.recovery_codes.len()
+        );
+        return Ok(());
+    }
+    ud.add_failure(id, ip);
+    write_user(id, ud).await?;
+    Err(refused(
+        StatusCode::UNAUTHORIZED,
+        "incorrect totp code".to_string(),
+    ))
+}
+
+// checks the password and keeps count of the failures per client ip and account, a locked
+// out ip or account is refused before the password is looked at, the user comes back with
+// LOGIN_WRITE held so the caller's changes are written before the next login reads it
+async fn verify(
+    id: &str,
+    password: &str,
+    ip: &str,
+) -> Result<(MutexGuard<'static, ()>, UserData), Box<dyn std::error::Error>> {
+    let missing = || {
+        let msg = format!("no record found for user {} (have you registered ?)", id);
+        log::error!("{}", msg);
+        get_error(msg)
+    };
+    let ud = read_user(id).await?.ok_or_else(missing)?;
+    let now = Local::now().timestamp();
+    let locked_until = ud
+        .failures
+        .get(ip)
+        .map(|f| f.locked_until)
+        .unwrap_or(0)
+        .max(ud.account_failures.locked_until);
+    if locked_until > now {
+        return Err(refused(
+            StatusCode::TOO_MANY_REQUESTS,
+            format!(
+                "user {} is locked, try again in {}s",
+                id,
+                locked_until - now
+            ),
+        ));
+    }
+    // the hash is slow, it's checked off the runtime and before LOGIN_WRITE is taken
+    let check = |stored: String| {
+        let password = password.to_string();
+        tokio::task::spawn_blocking(move || password_matches(&stored, &password))
+    };
+    let mut matched = check(ud.password.clone()).await?;
+    let write = LOGIN_WRITE.lock().await;
+    // read again, another login may have written the record meanwhile
+    let mut current = read_user(id).await?.ok_or_else(missing)?;
+    if current.password != ud.password {
+        matched = check(current.password.clone()).await?;
+    }
+    if !matched {
+        current.add_failure(id, ip);
+        write_user(id, &current).await?;
+        return Err(refused(
+            StatusCode::UNAUTHORIZED,
+            "incorrect credentials".to_string(),
+        ));
+    }
+    let mut changed = false;
+    if !password_hashed(&current.password) {
+        current.password = hash_password(password)?;
+        log::info!("password of user {} is stored hashed now", id);
+        changed = true;
+    }
+    // with totp the failures are cleared once the code is right as well
+    if !current.totp_enabled && current.clear_failures(ip) {
+        changed = true;
+    }
+    if changed {
+        write_user(id, &current).await?;
+    }
+    Ok((write, current))
+}
+
+/// reads a user record from the login tree (used by the admin cli and checks)
+pub async fn read_user(id: &str) -> Result<Option<UserData>, Box<dyn std::error::Error>> {
     let tree = get_opts("login".to_string())?;
-    // start transaction
     let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
-    let key = Bytes::from(id.clone());
+    let key = Bytes::from(id.to_string());
     let res = txn.get(&key).map_err(|e| get_error(e.to_string()))?;
-    // commit transaction
     txn.commit().await?;
     tree.close().await?;
     match res {
-        Some(val) => {
-            let ud: UserData =
-                serde_json::from_slice(&val).map_err(|e| get_error(e.to_string()))?;
-            if ud.password != password {
-                return Err(get_error("incorrect credentials".to_string()));
-            }
-            Ok("login successful".to_string())
+        Some(val) => Ok(Some(
+            serde_json::from_slice(&val).map_err(|e| get_error(e.to_string()))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+pub async fn write_user(id: &str, ud: &UserData) -> Result<(), Box<dyn std::error::Error>> {
+    let tree = get_opts("login".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let key = Bytes::from(id.to_string());
+    let value = Bytes::from(serde_json::to_string(ud)?);
+    txn.set(&key, &value)
+        .map_err(|e| get_error(e.to_string()))?;
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(())
+}
+
+pub async fn list_users() -> Result<Vec<(String, UserData)>, Box<dyn std::error::Error>> {
+    let tree = get_opts("login".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    // 0xff never appears in utf-8 so this covers every user name
+    let results = txn.range(b"", &[0xff], None)?;
+    let mut users = vec![];
+    for x in results.into_iter() {
+        let (key, value) = x.map_err(|e| get_error(e.to_string()))?;
+        let Some(value) = value else {
+            continue;
+        };
+        let ud: UserData = serde_json::from_slice(&value)?;
+        users.push((String::from_utf8(key.to_vec())?, ud));
+    }
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_are_stored_hashed() {
+        let stored = hash_password("Secret-pass-123").unwrap();
+        assert!(password_hashed(&stored));
+        assert!(!stored.contains("Secret-pass-123"));
+        assert_eq!(stored.split('$').count(), 4);
+        assert!(password_matches(&stored, "Secret-pass-123"));
+        assert!(!password_matches(&stored, "Secret-pass-124"));
+        assert!(!password_matches(&stored, ""));
+        // a new salt every time
+        assert_ne!(stored, hash_password("Secret-pass-123").unwrap());
+    }
+
+    #[test]
+    fn plain_passwords_still_match_until_migrated() {
+        assert!(!password_hashed("Secret-pass-123"));
+        assert!(password_matches("Secret-pass-123", "Secret-pass-123"));
+        assert!(!password_matches("Secret-pass-123", "Secret-pass-12"));
+    }
+
+    #[test]
+    fn malformed_hashes_never_match() {
+        let stored = hash_password("Secret-pass-123").unwrap();
+        let parts: Vec<&str> = stored.split('$').collect();
+        let long = URL_SAFE_NO_PAD.encode([0u8; 64]);
+        let cases = [
+            format!("pbkdf2-sha256${}${}", parts[1], parts[2]),
+            format!("pbkdf2-sha256$0${}${}", parts[2], parts[3]),
+            format!("pbkdf2-sha256$x${}${}", parts[2], parts[3]),
+            format!("pbkdf2-sha256${}$!!${}", parts[1], parts[3]),
+            format!("pbkdf2-sha256${}${}$", parts[1], parts[2]),
+            format!("pbkdf2-sha256${}${}${}", parts[1], parts[2], long),
+            format!("{}$extra", stored),
+        ];
+        for stored in cases {
+            assert!(!password_matches(&stored, "Secret-pass-123"), "{}", stored);
         }
-        None => {
-            let msg = format!("no record found for user {} (have you registered ?)", id);
-            log::error!("{}", msg);
-            Err(get_error(msg))
+    }
+
+    #[test]
+    fn failures_lock_at_the_threshold() {
+        let mut failures = Failures::default();
+        assert!(!failures.add(3, 900, 1000));
+        assert!(!failures.add(3, 900, 1001));
+        assert!(failures.add(3, 900, 1002));
+        assert_eq!(failures.locked_until, 1902);
+        assert_eq!(failures.count, 0);
+    }
+
+    #[test]
+    fn failures_never_lock_with_zero_attempts() {
+        let mut failures = Failures::default();
+        for now in 1000..1100 {
+            assert!(!failures.add(0, 900, now));
+        }
+        assert_eq!(failures.locked_until, 0);
+    }
+
+    #[test]
+    fn failures_count_expires_after_the_duration() {
+        let mut failures = Failures::default();
+        failures.add(3, 900, 1000);
+        failures.add(3, 900, 1001);
+        // the last failure is older than the duration, counting starts again
+        assert!(!failures.add(3, 900, 1901));
+        assert_eq!(failures.count, 1);
+    }
+
+    #[test]
+    fn prune_drops_expired_ips() {
+        let mut map = HashMap::new();
+        map.insert(
+            "10.0.0.1".to_string(),
+            Failures {
+                count: 2,
+                locked_until: 0,
+                last: 1000,
+            },
+        );
+        map.insert(
+            "10.0.0.2".to_string(),
+            Failures {
+                count: 0,
+                locked_until: 2500,
+                last: 1000,
+            },
+        );
+        map.insert(
+            "10.0.0.3".to_string(),
+            Failures {
+                count: 1,
+                locked_until: 0,
+                last: 1800,
+            },
+        );
+        prune_failures(&mut map, 900, 2000);
+        let mut ips: Vec<&String> = map.keys().collect();
+        ips.sort();
+        assert_eq!(ips, vec!["10.0.0.2", "10.0.0.3"]);
+    }
+
+    #[test]
+    fn prune_caps_the_ips() {
+        let mut map = HashMap::new();
+        for i in 0..MAX_FAILURE_IPS + 10 {
+            map.insert(
+                format!("10.0.{}.{}", i / 256, i % 256),
+                Failures {
+                    count: 1,
+                    locked_until: 0,
+                    last: 1000 + i as i64,
+                },
+            );
         }
+        prune_failures(&mut map, 900, 1200);
+        assert_eq!(map.len(), MAX_FAILURE_IPS);
+        // the least recent are gone
+        assert!(!map.contains_key("10.0.0.9"));
+        assert!(map.contains_key("10.0.0.10"));
     }
 }
@@ -0,0 +1,126 @@
+use pulldown_cmark::{CodeBlockKind, Event, Options, Parser, Tag, TagEnd, html};
+use std::sync::LazyLock;
+use syntect::highlighting::ThemeSet;
+use syntect::html::{ClassStyle, ClassedHTMLGenerator, css_for_theme_with_class_style};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
+
+// prefixed so highlight classes don't clash with the console stylesheet
+const CLASS_STYLE: ClassStyle = ClassStyle::SpacedPrefixed { prefix: "hl-" };
+const THEME: &str = "base16-ocean.dark";
+
+static SYNTAX_SET: LazyLock<SyntaxSet> = LazyLock::new(SyntaxSet::load_defaults_newlines);
+static THEME_CSS: LazyLock<String> = LazyLock::new(|| {
+    let ts = ThemeSet::load_defaults();
+    css_for_theme_with_class_style(&ts.themes[THEME], CLASS_STYLE).unwrap_or_default()
+});
+
+/// renders commonmark (with gfm tables) to sanitized html,
+/// fenced code blocks are syntax highlighted
+pub fn render_html(document: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let parser = Parser::new_ext(document, options);
+    let mut events: Vec<Event> = vec![];
+    let mut code_lang: Option<String> = None;
+    let mut code = String::new();
+    for event in parser {
+        match event {
+            Event::Start(Tag::CodeBlock(kind)) => {
+                code_lang = match kind {
+                    CodeBlockKind::Fenced(lang) => Some(lang.to_string()),
+                    CodeBlockKind::Indented => Some("".to_string()),
+                };
+                code.clear();
+            }
+            Event::Text(text) if code_lang.is_some() => {
+                code.push_str(&text);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                let lang = code_lang.take().unwrap_or_default();
+                events.push(Event::Html(highlight_code(&lang, &code).into()));
+            }
+            _ => events.push(event),
+        }
+    }
+    let mut unsafe_html = String::new();
+    html::push_html(&mut unsafe_html, events.into_iter());
+    let safe_html = ammonia::Builder::default()
+        .add_tag_attributes("pre", &["class"])
+        .add_tag_attributes("code", &["class"])
+        .add_tag_attributes("span", &["class"])
+        .clean(&unsafe_html)
+        .to_string();
+    format!("<style>{}</style>\n{}", *THEME_CSS, safe_html)
+}
+
+fn highlight_code(lang: &str, code: &str) -> String {
+    let ss = &*SYNTAX_SET;
+    // info strings can carry attributes after the language (```rust ignore)
+    let token = lang.split_whitespace().next().unwrap_or("");
+    let syntax = ss
+        .find_syntax_by_token(token)
+        .unwrap_or_else(|| ss.find_syntax_plain_text());
+    let mut generator = ClassedHTMLGenerator::new_with_class_style(syntax, ss, CLASS_STYLE);
+    for line in LinesWithEndings::from(code) {
+        if generator
+            .parse_html_for_line_which_includes_newline(line)
+            .is_err()
+        {
+            return format!("<pre><code>{}</code></pre>", ammonia::clean_text(code));
+        }
+    }
+    format!(
+        "<pre class=\"hl-code\"><code>{}</code></pre>",
+        generator.finalize()
+    )
+}
+
+/// wraps the rendered document in a self-contained html page for export
+pub fn render_page(title: &str, document: &str) -> String {
+    format!(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1.0">
+    <title>{}</title>
+    <style>
+        body {{ font-family: Arial, sans-serif; max-width: 900px; margin: 30px auto; padding: 0 15px; line-height: 1.5; }}
+        table {{ border-collapse: collapse; }}
+        th, td {{ border: 1px solid #aaaaaa; padding: 6px 10px; text-align: left; }}
+        pre {{ padding: 12px; overflow-x: auto; border-radius: 4px; }}
+    </style>
+</head>
+<body>
+{}
+</body>
+</html>
+"#,
+        ammonia::clean_text(title),
+        render_html(document)
+    )
+}
+
+/// strips markdown syntax leaving the readable text
+pub fn render_text(document: &str) -> String {
+    let options = Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH;
+    let mut text = String::new();
+    for event in Parser::new_ext(document, options) {
+        match event {
+            Event::Text(t) | Event::Code(t) => text.push_str(&t),
+            Event::SoftBreak | Event::HardBreak => text.push('\n'),
+            Event::Start(Tag::Item) => text.push_str("- "),
+            Event::End(TagEnd::TableCell) => text.push('\t'),
+            Event::End(
+                TagEnd::Paragraph
+                | TagEnd::Heading(_)
+                | TagEnd::Item
+                | TagEnd::CodeBlock
+                | TagEnd::TableHead
+                | TagEnd::TableRow,
+            ) => text.push('\n'),
+            _ => {}
+        }
+    }
+    text
+}
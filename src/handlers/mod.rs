@@ -2,5 +2,12 @@ pub mod common;
 pub mod formdata;
 pub mod interface;
 pub mod login;
+pub mod markdown;
+pub mod oidc;
+pub mod proxy;
+pub mod ratelimit;
+pub mod request;
 pub mod service;
+pub mod session;
+pub mod totp;
 pub mod view;
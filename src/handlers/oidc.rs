@@ -0,0 +1,961 @@
+use crate::certs::controller::load_public_key;
+use crate::config::process::Oidc;
+use crate::handlers::common::{get_error, get_opts};
+use crate::handlers::login::{
+    LOGIN_WRITE, Refused, UserData, hash_password, read_user, token_hash, write_user,
+};
+use crate::handlers::request::username_problem;
+use aws_lc_rs::hmac;
+use aws_lc_rs::signature::{
+    ECDSA_P256_SHA256_FIXED, RSA_PKCS1_2048_8192_SHA256, RsaPublicKeyComponents, UnparsedPublicKey,
+};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use chrono::Local;
+use custom_logger as log;
+use http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE};
+use http::{Request, StatusCode};
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::rt::TokioExecutor;
+use rustls::{ClientConfig, RootCertStore};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use url::form_urlencoded;
+
+type OidcResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// a login has this long to come back from the provider
+const PENDING_TTL: Duration = Duration::from_secs(600);
+// logins in progress at most, more are refused until some come back or expire
+const MAX_PENDING: usize = 1000;
+// clock difference accepted for exp
+const LEEWAY: i64 = 60;
+// discovery and jwks are fetched again after CACHE_TTL, the jwks sooner when no key matches
+// the id token (the provider rotated its keys) but not more than once every JWKS_REFETCH
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+const JWKS_REFETCH: Duration = Duration::from_secs(60);
+
+/// holds the hash of the state, the callback has to come back to the browser that started it
+pub const STATE_COOKIE: &str = "webconsole_oidc";
+
+static OIDC: RwLock<Option<Oidc>> = RwLock::new(None);
+
+// issuer, document and when it was fetched
+static DISCOVERY: Mutex<Option<(String, Discovery, Instant)>> = Mutex::new(None);
+// jwks_uri, keys and when they were fetched
+static JWKS: Mutex<Option<(String, Value, Instant)>> = Mutex::new(None);
+
+// state of the logins sent to the provider
+static PENDING: LazyLock<Mutex<HashMap<String, Pending>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+struct Pending {
+    verifier: String,
+    nonce: String,
+    created: Instant,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct Discovery {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+pub fn init(oidc: Option<Oidc>) {
+    if let Some(oidc) = &oidc {
+        log::info!("openid connect login with {}", oidc.issuer);
+    }
+    *OIDC.write().unwrap() = oidc;
+}
+
+pub fn enabled() -> bool {
+    OIDC.read().map(|oidc| oidc.is_some()).unwrap_or(false)
+}
+
+fn config() -> OidcResult<Oidc> {
+    let oidc = OIDC.read().map_err(|e| e.to_string())?;
+    Ok(oidc.clone().ok_or("openid connect is not configured")?)
+}
+
+fn client(oidc: &Oidc) -> OidcResult<Client<HttpsConnector<HttpConnector>, Full<Bytes>>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca_bundle) = &oidc.ca_bundle {
+        for cert in load_public_key(ca_bundle.clone())? {
+            roots.add(cert)?;
+        }
+    }
+    let tls = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .build();
+    Ok(Client::builder(TokioExecutor::new()).build(connector))
+}
+
+async fn get_json<T: DeserializeOwned>(
+    client: &Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    url: &str,
+) -> OidcResult<T> {
+    let req = Request::get(url)
+        .header(ACCEPT, "application/json")
+        .body(Full::default())?;
+    let res = client.request(req).await?;
+    let status = res.status();
+    let body = res.into_body().collect().await?.to_bytes();
+    if !status.is_success() {
+        return Err(format!("{} returned {}", url, status).into());
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn discover(
+    client: &Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    oidc: &Oidc,
+) -> OidcResult<Discovery> {
+    let issuer = oidc.issuer.trim_end_matches('/');
+    let cached = DISCOVERY
+        .lock()
+        .map_err(|e| e.to_string())?
+        .clone()
+        .filter(|(cached, _, fetched)| cached == issuer && fetched.elapsed() < CACHE_TTL);
+    if let Some((_, discovery, _)) = cached {
+        return Ok(discovery);
+    }
+    let url = format!("{}/.well-known/openid-configuration", issuer);
+    let discovery: Discovery = get_json(client, &url).await?;
+    if discovery.issuer.trim_end_matches('/') != issuer {
+        return Err(format!(
+            "the provider calls itself {}, not {}",
+            discovery.issuer, oidc.issuer
+        )
+        .into());
+    }
+    *DISCOVERY.lock().map_err(|e| e.to_string())? =
+        Some((issuer.to_string(), discovery.clone(), Instant::now()));
+    Ok(discovery)
+}
+
+// the provider keys, refetch takes a copy older than JWKS_REFETCH as stale
+async fn provider_keys(
+    client: &Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    jwks_uri: &str,
+    refetch: bool,
+) -> OidcResult<Value> {
+    let max_age = if refetch { JWKS_REFETCH } else { CACHE_TTL };
+    let cached = JWKS
+        .lock()
+        .map_err(|e| e.to_string())?
+        .clone()
+        .filter(|(cached, _, fetched)| cached == jwks_uri && fetched.elapsed() < max_age);
+    if let Some((_, keys, _)) = cached {
+        return Ok(keys);
+    }
+    let keys: Value = get_json(client, jwks_uri).await?;
+    *JWKS.lock().map_err(|e| e.to_string())? =
+        Some((jwks_uri.to_string(), keys.clone(), Instant::now()));
+    Ok(keys)
+}
+
+fn random(len: usize) -> OidcResult<String> {
+    let mut bytes = vec![0u8; len];
+    aws_lc_rs::rand::fill(&mut bytes).map_err(|_| "unable to generate random bytes")?;
+    Ok(URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// the provider url the browser is sent to, with state, nonce and the pkce (S256) challenge,
+/// and the state for STATE_COOKIE
+pub async fn authorization_url() -> OidcResult<(String, String)> {
+    let oidc = config()?;
+    let discovery = discover(&client(&oidc)?, &oidc).await?;
+    let (state, nonce, verifier) = (random(24)?, random(24)?, random(32)?);
+    let challenge = pkce_challenge(&verifier);
+    {
+        let mut pending = PENDING.lock().map_err(|e| e.to_string())?;
+        pending.retain(|_, login| login.created.elapsed() < PENDING_TTL);
+        if pending.len() >= MAX_PENDING {
+            return Err(Box::new(Refused {
+                status: StatusCode::SERVICE_UNAVAILABLE,
+                msg: "too many logins in progress, try again later".to_string(),
+            }));
+        }
+        pending.insert(
+            state.clone(),
+            Pending {
+                verifier,
+                nonce: nonce.clone(),
+                created: Instant::now(),
+            },
+        );
+    }
+    let scopes = oidc
+        .scopes
+        .unwrap_or(vec![
+            "openid".to_string(),
+            "profile".to_string(),
+            "email".to_string(),
+        ])
+        .join(" ");
+    let query = form_urlencoded::Serializer::new(String::new())
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &oidc.client_id)
+        .append_pair("redirect_uri", &oidc.redirect_uri)
+        .append_pair("scope", &scopes)
+        .append_pair("state", &state)
+        .append_pair("nonce", &nonce)
+        .append_pair("code_challenge", &challenge)
+        .append_pair("code_challenge_method", "S256")
+        .finish();
+    let separator = match discovery.authorization_endpoint.contains('?') {
+        true => '&',
+        false => '?',
+    };
+    Ok((
+        format!("{}{}{}", discovery.authorization_endpoint, separator, query),
+        state,
+    ))
+}
+
+fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Set-Cookie value with the hash of the state, an empty state clears the cookie
+pub fn state_cookie(state: &str, secure: bool) -> String {
+    let (value, max_age) = match state.is_empty() {
+        true => (String::new(), 0),
+        false => (token_hash(state), PENDING_TTL.as_secs()),
+    };
+    let secure = if secure { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path=/webconsole/oidc; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        STATE_COOKIE, value, max_age, secure
+    )
+}
+
+// the state and code of the callback query, or what the provider refused
+fn callback_params(query: &str) -> OidcResult<(String, String)> {
+    let mut params: HashMap<String, String> = form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+    if let Some(error) = params.get("error") {
+        return Err(format!(
+            "the provider refused the login: {} {}",
+            error,
+            params
+                .get("error_description")
+                .map(String::as_str)
+                .unwrap_or_default()
+        )
+        .into());
+    }
+    let state = params.remove("state").ok_or("the callback has no state")?;
+    let code = params.remove("code").ok_or("the callback has no code")?;
+    Ok((state, code))
+}
+
+// the state has to come back to the browser that started the login (STATE_COOKIE holds its
+// hash) so a login started by someone else can't be finished here, it's only used once
+fn take_pending(state: &str, cookie: Option<&str>) -> OidcResult<Pending> {
+    if cookie != Some(token_hash(state).as_str()) {
+        return Err("the login was started in another browser, start again".into());
+    }
+    let pending = PENDING
+        .lock()
+        .map_err(|e| e.to_string())?
+        .remove(state)
+        .filter(|login| login.created.elapsed() < PENDING_TTL)
+        .ok_or("unknown or expired login, start again")?;
+    Ok(pending)
+}
+
+/// finishes the login: the code is exchanged, the id token checked and the console user
+/// looked up (or added) with the role from the claims, returns the user name
+pub async fn callback(query: &str, cookie: Option<&str>) -> OidcResult<String> {
+    let oidc = config()?;
+    let (state, code) = callback_params(query)?;
+    let pending = take_pending(&state, cookie)?;
+    let client = client(&oidc)?;
+    let discovery = discover(&client, &oidc).await?;
+
+    let form = form_urlencoded::Serializer::new(String::new())
+        .append_pair("grant_type", "authorization_code")
+        .append_pair("code", &code)
+        .append_pair("redirect_uri", &oidc.redirect_uri)
+        .append_pair("client_id", &oidc.client_id)
+        .append_pair("code_verifier", &pending.verifier)
+        .finish();
+    let mut req = Request::post(&discovery.token_endpoint)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(ACCEPT, "application/json");
+    // client_secret_basic, both parts form encoded (rfc 6749 section 2.3.1)
+    if let Some(secret) = &oidc.client_secret {
+        let encode = |s: &str| form_urlencoded::byte_serialize(s.as_bytes()).collect::<String>();
+        let basic = STANDARD.encode(format!("{}:{}", encode(&oidc.client_id), encode(secret)));
+        req = req.header(AUTHORIZATION, format!("Basic {}", basic));
+    }
+    let res = client.request(req.body(Full::from(form))?).await?;
+    let status = res.status();
+    let body = res.into_body().collect().await?.to_bytes();
+    if !status.is_success() {
+        return Err(format!(
+            "the token request returned {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )
+        .into());
+    }
+    let token: TokenResponse = serde_json::from_slice(&body)?;
+    let claims =
+        verify_id_token(&client, &oidc, &discovery, &token.id_token, &pending.nonce).await?;
+    local_user(&oidc, &claims).await
+}
+
+// the provider keys are only needed for RS256 and ES256
+async fn verify_id_token(
+    client: &Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    oidc: &Oidc,
+    discovery: &Discovery,
+    token: &str,
+    nonce: &str,
+) -> OidcResult<Value> {
+    let (header, _, _, _) = jwt_parts(token)?;
+    let keys = match header["alg"].as_str() {
+        Some(alg @ ("RS256" | "ES256")) => {
+            let keys = provider_keys(client, &discovery.jwks_uri, false).await?;
+            match find_key(&keys, alg, header["kid"].as_str()) {
+                Some(_) => keys,
+                None => provider_keys(client, &discovery.jwks_uri, true).await?,
+            }
+        }
+        _ => Value::Null,
+    };
+    check_id_token(
+        oidc,
+        &discovery.issuer,
+        token,
+        nonce,
+        &keys,
+        Local::now().timestamp(),
+    )
+}
+
+// header, claims, signature and the signed message of a jwt
+fn jwt_parts(token: &str) -> OidcResult<(Value, Value, Vec<u8>, String)> {
+    let parts: Vec<&str> = token.split('.').collect();
+    if parts.len() != 3 {
+        return Err("the id token is not a signed jwt".into());
+    }
+    let header: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[0])?)?;
+    let claims: Value = serde_json::from_slice(&URL_SAFE_NO_PAD.decode(parts[1])?)?;
+    let signature = URL_SAFE_NO_PAD.decode(parts[2])?;
+    Ok((
+        header,
+        claims,
+        signature,
+        format!("{}.{}", parts[0], parts[1]),
+    ))
+}
+
+// the jwk of the provider keys for the algorithm and key id
+fn find_key<'a>(keys: &'a Value, alg: &str, kid: Option<&str>) -> Option<&'a Value> {
+    let kty = if alg == "RS256" { "RSA" } else { "EC" };
+    keys["keys"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|jwk| jwk["kty"] == kty && (kid.is_none() || jwk["kid"].as_str() == kid))
+}
+
+// signature (RS256, ES256 from the provider keys or HS256 with the client secret), issuer,
+// audience, expiry (against now) and nonce
+fn check_id_token(
+    oidc: &Oidc,
+    issuer: &str,
+    token: &str,
+    nonce: &str,
+    keys: &Value,
+    now: i64,
+) -> OidcResult<Value> {
+    let (header, claims, signature, message) = jwt_parts(token)?;
+    let bad_signature = |_| "the id token signature is invalid";
+    match header["alg"].as_str() {
+        Some("HS256") => {
+            let secret = oidc
+                .client_secret
+                .as_ref()
+                .ok_or("an HS256 id token needs the client secret")?;
+            let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+            hmac::verify(&key, message.as_bytes(), &signature).map_err(bad_signature)?;
+        }
+        Some(alg @ ("RS256" | "ES256")) => {
+            let jwk = find_key(keys, alg, header["kid"].as_str())
+                .ok_or("no key in the provider jwks matches the id token")?;
+            let field = |name: &str| -> OidcResult<Vec<u8>> {
+                let value = jwk[name].as_str().ok_or("the jwk is incomplete")?;
+                Ok(URL_SAFE_NO_PAD.decode(value)?)
+            };
+            if alg == "RS256" {
+                let key = RsaPublicKeyComponents {
+                    n: field("n")?,
+                    e: field("e")?,
+                };
+                key.verify(&RSA_PKCS1_2048_8192_SHA256, message.as_bytes(), &signature)
+                    .map_err(bad_signature)?;
+            } else {
+                // uncompressed point 0x04 || x || y
+                let point = [vec![4u8], field("x")?, field("y")?].concat();
+                UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, point)
+                    .verify(message.as_bytes(), &signature)
+                    .map_err(bad_signature)?;
+            }
+        }
+        alg => return Err(format!("id token algorithm {:?} is not supported", alg).into()),
+    }
+
+    if claims["iss"].as_str() != Some(issuer) {
+        return Err("the id token is from another issuer".into());
+    }
+    let audience = match &claims["aud"] {
+        Value::String(aud) => *aud == oidc.client_id,
+        Value::Array(auds) => auds.iter().any(|aud| *aud == oidc.client_id.as_str()),
+        _ => false,
+    };
+    if !audience {
+        return Err("the id token is for another client".into());
+    }
+    let expires = claims["exp"].as_i64().ok_or("the id token has no exp")?;
+    if expires + LEEWAY < now {
+        return Err("the id token has expired".into());
+    }
+    if claims["nonce"].as_str() != Some(nonce) {
+        return Err("the id token nonce doesn't match the login".into());
+    }
+    Ok(claims)
+}
+
+// admin when role_claim (a string or a list) holds one of admin_values, user otherwise,
+// nothing without role_claim (the console role is kept)
+fn claim_role(oidc: &Oidc, claims: &Value) -> Option<String> {
+    oidc.role_claim.as_ref().map(|claim| {
+        let values: Vec<&str> = match &claims[claim.as_str()] {
+            Value::String(value) => vec![value.as_str()],
+            Value::Array(values) => values.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        let admin_values = oidc.admin_values.clone().unwrap_or_default();
+        match values
+            .iter()
+            .any(|value| admin_values.iter().any(|admin| admin == value))
+        {
+            true => "admin".to_string(),
+            false => "user".to_string(),
+        }
+    })
+}
+
+/// the key of a provider identity, the subject is only unique for its issuer
+pub fn identity(issuer: &str, subject: &str) -> String {
+    format!("{} {}", issuer.trim_end_matches('/'), subject)
+}
+
+/// the console user linked to the identity (oidc tree)
+pub async fn read_link(identity: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let tree = get_opts("oidc".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let key = Bytes::from(identity.to_string());
+    let res = txn.get(&key).map_err(|e| get_error(e.to_string()))?;
+    txn.commit().await?;
+    tree.close().await?;
+    match res {
+        Some(val) => Ok(Some(String::from_utf8(val.to_vec())?)),
+        None => Ok(None),
+    }
+}
+
+/// links the identity to the user, a link the user had before is dropped
+pub async fn link_user(
+    name: &str,
+    ud: &mut UserData,
+    identity: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let other = read_link(identity).await?.filter(|linked| linked != name);
+    if let Some(other) = other {
+        return Err(format!("{} is linked to user {}", identity, other).into());
+    }
+    let tree = get_opts("oidc".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    if let Some(old) = ud.oidc_identity.as_ref().filter(|old| *old != identity) {
+        txn.delete(&Bytes::from(old.clone()))
+            .map_err(|e| get_error(e.to_string()))?;
+    }
+    txn.set(
+        &Bytes::from(identity.to_string()),
+        &Bytes::from(name.to_string()),
+    )
+    .map_err(|e| get_error(e.to_string()))?;
+    txn.commit().await?;
+    tree.close().await?;
+    ud.oidc_identity = Some(identity.to_string());
+    write_user(name, ud).await
+}
+
+// the console user of the identity (issuer and sub), an unknown identity gets a new account
+// named after username_claim when nobody has that name yet, an existing account is only
+// used once an admin links it (user link) so a provider name can't take it over
+async fn local_user(oidc: &Oidc, claims: &Value) -> OidcResult<String> {
+    let _write = LOGIN_WRITE.lock().await;
+    let issuer = claims["iss"].as_str().ok_or("the id token has no iss")?;
+    let subject = claims["sub"].as_str().ok_or("the id token has no sub")?;
+    let identity = identity(issuer, subject);
+    let role = claim_role(oidc, claims);
+    let linked = read_link(&identity).await.map_err(|e| e.to_string())?;
+    let (user, mut ud) = match linked {
+        Some(user) => {
+            let ud = read_user(&user).await.map_err(|e| e.to_string())?;
+            // the record has to point back, a stale link gives no account
+            let Some(ud) = ud.filter(|ud| ud.oidc_identity.as_deref() == Some(identity.as_str()))
+            else {
+                return Err(
+                    format!("{} is linked to user {} without a record", identity, user).into(),
+                );
+            };
+            if ud.disabled || ud.pending {
+                return Err(format!("user {} can't log in", user).into());
+            }
+            (user, ud)
+        }
+        None => {
+            let username_claim = oidc
+                .username_claim
+                .as_deref()
+                .unwrap_or("preferred_username");
+            let user = claims[username_claim]
+                .as_str()
+                .ok_or(format!("the id token has no {} claim", username_claim))?;
+            if let Some(problem) = username_problem(user) {
+                return Err(format!(
+                    "{} {:?} can't be a console user, {}",
+                    username_claim, user, problem
+                )
+                .into());
+            }
+            if read_user(user).await.map_err(|e| e.to_string())?.is_some() {
+                return Err(format!(
+                    "user {} exists and isn't linked to {}, an admin can link it with \
+                     user link {} --subject {}",
+                    user, issuer, user, subject
+                )
+                .into());
+            }
+            if !oidc.create_users.unwrap_or(true) {
+                return Err(format!("user {} has no console account", user).into());
+            }
+            log::info!("adding user {} from openid connect ({})", user, identity);
+            // the password is never handed out, the user logs in through the provider
+            let password = hash_password(&random(32)?).map_err(|e| e.to_string())?;
+            let ud = UserData::new(password, "user".to_string(), false);
+            (user.to_string(), ud)
+        }
+    };
+    if let Some(role) = role {
+        ud.role = role;
+    }
+    link_user(&user, &mut ud, &identity)
+        .await
+        .map_err(|e| e.to_string())?;
+    log::info!(
+        "user {} logged in with openid connect (role {})",
+        user,
+        ud.role
+    );
+    Ok(user)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_lc_rs::rand::SystemRandom;
+    use aws_lc_rs::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+    use serde_json::json;
+
+    const ISSUER: &str = "https://sso.example.com";
+
+    fn oidc() -> Oidc {
+        Oidc {
+            issuer: ISSUER.to_string(),
+            client_id: "console".to_string(),
+            client_secret: Some("secret".to_string()),
+            role_claim: Some("groups".to_string()),
+            admin_values: Some(vec!["admins".to_string()]),
+            ..Default::default()
+        }
+    }
+
+    fn claims(sub: &str, name: &str) -> Value {
+        json!({
+            "iss": ISSUER,
+            "sub": sub,
+            "aud": "console",
+            "exp": 2000,
+            "nonce": "n-0S6_WzA2Mj",
+            "preferred_username": name,
+            "groups": ["staff"],
+        })
+    }
+
+    fn jwt(header: Value, claims: &Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = URL_SAFE_NO_PAD.encode(sign(message.as_bytes()));
+        format!("{}.{}", message, signature)
+    }
+
+    fn hs256(claims: &Value, secret: &str) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        jwt(json!({"alg": "HS256", "typ": "JWT"}), claims, |message| {
+            hmac::sign(&key, message).as_ref().to_vec()
+        })
+    }
+
+    fn check(token: &str, keys: &Value) -> OidcResult<Value> {
+        check_id_token(&oidc(), ISSUER, token, "n-0S6_WzA2Mj", keys, 1000)
+    }
+
+    fn refused<T, E: ToString>(res: Result<T, E>) -> String {
+        match res {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn admin_role_from_a_list_or_string_claim() {
+        let mut claims = claims("1", "alice");
+        claims["groups"] = json!(["staff", "admins"]);
+        assert_eq!(claim_role(&oidc(), &claims).as_deref(), Some("admin"));
+        claims["groups"] = json!("admins");
+        assert_eq!(claim_role(&oidc(), &claims).as_deref(), Some("admin"));
+    }
+
+    #[test]
+    fn user_role_without_an_admin_value() {
+        let mut claims = claims("1", "alice");
+        assert_eq!(claim_role(&oidc(), &claims).as_deref(), Some("user"));
+        claims["groups"] = json!(42);
+        assert_eq!(claim_role(&oidc(), &claims).as_deref(), Some("user"));
+        claims.as_object_mut().unwrap().remove("groups");
+        assert_eq!(claim_role(&oidc(), &claims).as_deref(), Some("user"));
+    }
+
+    #[test]
+    fn no_role_without_role_claim() {
+        let oidc = Oidc {
+            role_claim: None,
+            ..oidc()
+        };
+        assert_eq!(claim_role(&oidc, &claims("1", "alice")), None);
+    }
+
+    #[test]
+    fn pkce_challenge_of_rfc7636_example() {
+        // RFC 7636 appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn callback_errors() {
+        let error = refused(callback_params(
+            "error=access_denied&error_description=no+thanks&state=s",
+        ));
+        assert_eq!(
+            error,
+            "the provider refused the login: access_denied no thanks"
+        );
+        assert_eq!(
+            refused(callback_params("code=c")),
+            "the callback has no state"
+        );
+        assert_eq!(
+            refused(callback_params("state=s")),
+            "the callback has no code"
+        );
+        assert_eq!(
+            callback_params("state=s%2B1&code=c").unwrap(),
+            ("s+1".to_string(), "c".to_string())
+        );
+    }
+
+    fn pending(state: &str, created: Instant) {
+        PENDING.lock().unwrap().insert(
+            state.to_string(),
+            Pending {
+                verifier: "verifier".to_string(),
+                nonce: "nonce".to_string(),
+                created,
+            },
+        );
+    }
+
+    #[test]
+    fn state_needs_the_cookie_of_the_browser() {
+        pending("state-cookie", Instant::now());
+        let hash = token_hash("state-cookie");
+        let other = "the login was started in another browser, start again";
+        assert_eq!(refused(take_pending("state-cookie", None)), other);
+        assert_eq!(
+            refused(take_pending("state-cookie", Some(&token_hash("other")))),
+            other
+        );
+        // refused callbacks don't use the state up
+        assert_eq!(
+            take_pending("state-cookie", Some(&hash)).unwrap().verifier,
+            "verifier"
+        );
+        assert_eq!(
+            refused(take_pending("state-cookie", Some(&hash))),
+            "unknown or expired login, start again"
+        );
+    }
+
+    #[test]
+    fn expired_state_is_refused() {
+        let created = Instant::now() - PENDING_TTL - Duration::from_secs(1);
+        pending("state-expired", created);
+        assert_eq!(
+            refused(take_pending(
+                "state-expired",
+                Some(&token_hash("state-expired"))
+            )),
+            "unknown or expired login, start again"
+        );
+    }
+
+    #[test]
+    fn state_cookie_holds_the_hash() {
+        let cookie = state_cookie("abc", true);
+        assert!(cookie.starts_with(&format!("webconsole_oidc={};", token_hash("abc"))));
+        assert!(cookie.contains("Max-Age=600; HttpOnly; SameSite=Lax; Secure"));
+        assert!(
+            state_cookie("", false)
+                .starts_with("webconsole_oidc=; Path=/webconsole/oidc; Max-Age=0;")
+        );
+    }
+
+    #[test]
+    fn hs256_id_token() {
+        let claims = claims("1", "alice");
+        let token = hs256(&claims, "secret");
+        assert_eq!(check(&token, &Value::Null).unwrap(), claims);
+        assert_eq!(
+            refused(check(&hs256(&claims, "guessed"), &Value::Null)),
+            "the id token signature is invalid"
+        );
+        // claims changed after signing
+        let (message, signature) = token.rsplit_once('.').unwrap();
+        let (header, _) = message.split_once('.').unwrap();
+        let mut forged = claims.clone();
+        forged["groups"] = json!(["admins"]);
+        let forged = format!(
+            "{}.{}.{}",
+            header,
+            URL_SAFE_NO_PAD.encode(forged.to_string()),
+            signature
+        );
+        assert_eq!(
+            refused(check(&forged, &Value::Null)),
+            "the id token signature is invalid"
+        );
+    }
+
+    #[test]
+    fn id_token_claims_are_checked() {
+        let cases = [
+            (
+                "iss",
+                json!("https://evil.example.com"),
+                "the id token is from another issuer",
+            ),
+            ("aud", json!("other"), "the id token is for another client"),
+            (
+                "aud",
+                json!(["other", "more"]),
+                "the id token is for another client",
+            ),
+            ("exp", json!(1000 - LEEWAY - 1), "the id token has expired"),
+            (
+                "nonce",
+                json!("replayed"),
+                "the id token nonce doesn't match the login",
+            ),
+        ];
+        for (claim, value, error) in cases {
+            let mut claims = claims("1", "alice");
+            claims[claim] = value;
+            assert_eq!(
+                refused(check(&hs256(&claims, "secret"), &Value::Null)),
+                error
+            );
+        }
+        let mut claims = claims("1", "alice");
+        claims["aud"] = json!(["other", "console"]);
+        claims["exp"] = json!(1000 - LEEWAY);
+        assert!(check(&hs256(&claims, "secret"), &Value::Null).is_ok());
+    }
+
+    #[test]
+    fn unsigned_id_tokens_are_refused() {
+        let claims = claims("1", "alice");
+        let token = jwt(json!({"alg": "none"}), &claims, |_| vec![]);
+        assert_eq!(
+            refused(check(&token, &Value::Null)),
+            "id token algorithm Some(\"none\") is not supported"
+        );
+        assert_eq!(
+            refused(check("header.claims", &Value::Null)),
+            "the id token is not a signed jwt"
+        );
+    }
+
+    #[test]
+    fn es256_id_token_with_the_provider_keys() {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap();
+        let public = key.public_key().as_ref();
+        let keys = json!({"keys": [
+            {"kty": "RSA", "kid": "k1", "n": "AQAB", "e": "AQAB"},
+            {
+                "kty": "EC",
+                "kid": "k1",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&public[33..65]),
+            },
+        ]});
+        let claims = claims("1", "alice");
+        let sign = |message: &[u8]| key.sign(&rng, message).unwrap().as_ref().to_vec();
+        let token = jwt(json!({"alg": "ES256", "kid": "k1"}), &claims, sign);
+        assert_eq!(check(&token, &keys).unwrap(), claims);
+        let token = jwt(json!({"alg": "ES256", "kid": "k2"}), &claims, sign);
+        assert_eq!(
+            refused(check(&token, &keys)),
+            "no key in the provider jwks matches the id token"
+        );
+        // HS256 isn't taken as a way around the provider keys without a client secret
+        let public_client = Oidc {
+            client_secret: None,
+            ..oidc()
+        };
+        let token = hs256(&claims, "");
+        assert_eq!(
+            refused(check_id_token(
+                &public_client,
+                ISSUER,
+                &token,
+                "n-0S6_WzA2Mj",
+                &keys,
+                1000
+            )),
+            "an HS256 id token needs the client secret"
+        );
+    }
+
+    #[tokio::test]
+    async fn existing_accounts_need_a_link() {
+        let db_path = std::env::temp_dir().join(format!("oidc-test-{}", std::process::id()));
+        std::fs::create_dir_all(&db_path).unwrap();
+        *crate::MAP_LOOKUP.lock().unwrap() = Some(HashMap::from([(
+            "db_path".to_string(),
+            db_path.to_string_lossy().to_string(),
+        )]));
+        let oidc = oidc();
+        let local = UserData::new("Secret-pass-123".to_string(), "admin".to_string(), false);
+        write_user("alice", &local).await.unwrap();
+
+        // a provider user named like a local account gets nothing, the role is left alone
+        assert_eq!(
+            refused(local_user(&oidc, &claims("evil", "alice")).await),
+            format!(
+                "user alice exists and isn't linked to {}, an admin can link it with user link \
+                 alice --subject evil",
+                ISSUER
+            )
+        );
+        assert_eq!(read_user("alice").await.unwrap().unwrap().role, "admin");
+
+        // a new subject gets a new account, later logins find it by subject
+        assert_eq!(
+            local_user(&oidc, &claims("42", "bob")).await.unwrap(),
+            "bob"
+        );
+        assert_eq!(
+            local_user(&oidc, &claims("42", "robert")).await.unwrap(),
+            "bob"
+        );
+        assert_eq!(
+            refused(local_user(&oidc, &claims("43", "bob")).await),
+            format!(
+                "user bob exists and isn't linked to {}, an admin can link it with user link \
+                 bob --subject 43",
+                ISSUER
+            )
+        );
+
+        // the same subject at another provider is someone else
+        let mut other = claims("42", "carol");
+        other["iss"] = json!("https://other.example.com");
+        assert_eq!(local_user(&oidc, &other).await.unwrap(), "carol");
+
+        // linked by an admin the account is used, with the role from the claims
+        let mut ud = read_user("alice").await.unwrap().unwrap();
+        link_user("alice", &mut ud, &identity(ISSUER, "7"))
+            .await
+            .unwrap();
+        assert_eq!(
+            local_user(&oidc, &claims("7", "al")).await.unwrap(),
+            "alice"
+        );
+        assert_eq!(read_user("alice").await.unwrap().unwrap().role, "user");
+        assert_eq!(
+            refused(link_user("bob", &mut ud, &identity(ISSUER, "7")).await),
+            format!("{} 7 is linked to user alice", ISSUER)
+        );
+
+        let closed = Oidc {
+            create_users: Some(false),
+            ..oidc
+        };
+        assert_eq!(
+            refused(local_user(&closed, &claims("44", "dave")).await),
+            "user dave has no console account"
+        );
+        std::fs::remove_dir_all(&db_path).unwrap();
+    }
+}
@@ -0,0 +1,322 @@
+use hyper::HeaderMap;
+use std::net::{IpAddr, SocketAddr};
+
+/// the client as seen by the console, taken from the forwarding headers
+/// when the connection comes from a trusted proxy
+#[derive(Clone, Debug)]
+pub struct RemoteClient {
+    pub ip: IpAddr,
+    pub scheme: String,
+}
+
+pub fn remote_client(
+    peer: SocketAddr,
+    scheme: &str,
+    headers: &HeaderMap,
+    trusted_proxies: &[String],
+) -> RemoteClient {
+    let mut client = RemoteClient {
+        ip: peer.ip().to_canonical(),
+        scheme: scheme.to_string(),
+    };
+    if !is_trusted(client.ip, trusted_proxies) {
+        return client;
+    }
+    // Forwarded (RFC 7239) wins over the X-Forwarded-* headers
+    let (chain, proto) = match header(headers, "forwarded") {
+        Some(forwarded) => parse_forwarded(&forwarded),
+        None => (
+            header(headers, "x-forwarded-for")
+                .map(|v| v.split(',').map(parse_node).collect())
+                .unwrap_or_default(),
+            header(headers, "x-forwarded-proto"),
+        ),
+    };
+    // the nearest address that isn't one of our proxies is the client, an unknown or hidden
+    // hop (for=unknown, for=_proxy) ends the walk at the proxy that reported it
+    for ip in chain.iter().rev() {
+        let Some(ip) = ip else {
+            break;
+        };
+        client.ip = *ip;
+        if !is_trusted(*ip, trusted_proxies) {
+            break;
+        }
+    }
+    if let Some(proto) = proto {
+        let proto = proto.trim().to_lowercase();
+        if proto == "http" || proto == "https" {
+            client.scheme = proto;
+        }
+    }
+    client
+}
+
+// entries are addresses or cidr ranges (10.0.0.0/8, fd00::/8)
+fn is_trusted(ip: IpAddr, trusted_proxies: &[String]) -> bool {
+    trusted_proxies.iter().any(|entry| {
+        let (addr, prefix) = match entry.split_once('/') {
+            Some((addr, prefix)) => (addr, prefix.parse::<u32>().ok()),
+            None => (entry.as_str(), None),
+        };
+        let Ok(addr) = addr.trim().parse::<IpAddr>() else {
+            return false;
+        };
+        match (addr.to_canonical(), ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let prefix = prefix.unwrap_or(32).min(32);
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let prefix = prefix.unwrap_or(128).min(128);
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    })
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .collect();
+    match values.is_empty() {
+        true => None,
+        false => Some(values.join(",")),
+    }
+}
+
+// for=192.0.2.60;proto=https, for="[2001:db8::1]:4711"
+fn parse_forwarded(value: &str) -> (Vec<Option<IpAddr>>, Option<String>) {
+    let mut chain = vec![];
+    let mut proto = None;
+    for element in value.split(',') {
+        for pair in element.split(';') {
+            let Some((key, val)) = pair.split_once('=') else {
+                continue;
+            };
+            let val = val.trim().trim_matches('"');
+            match key.trim().to_lowercase().as_str() {
+                "for" => chain.push(parse_node(val)),
+                "proto" => proto = Some(val.to_string()),
+                _ => {}
+            }
+        }
+    }
+    (chain, proto)
+}
+
+// strips the optional port and ipv6 brackets
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip.to_canonical());
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip().to_canonical());
+    }
+    let node = node.strip_prefix('[').unwrap_or(node);
+    let node = node.split(']').next().unwrap_or(node);
+    let node = match node.matches(':').count() {
+        1 => node.split(':').next().unwrap_or(node),
+        _ => node,
+    };
+    node.parse::<IpAddr>().ok().map(|ip| ip.to_canonical())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Case<'a> = (&'a str, &'a [(&'static str, &'a str)], &'a str, &'a str);
+
+    fn client(peer: &str, headers: &[(&'static str, &str)]) -> (String, String) {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        let trusted = ["10.0.0.0/8".to_string(), "fd00::/8".to_string()];
+        let remote = remote_client(peer.parse().unwrap(), "http", &map, &trusted);
+        (remote.ip.to_string(), remote.scheme)
+    }
+
+    #[test]
+    fn forwarded_clients() {
+        let cases: &[Case] = &[
+            // an untrusted peer's headers are ignored
+            (
+                "203.0.113.9:4000",
+                &[
+                    ("x-forwarded-for", "198.51.100.1"),
+                    ("x-forwarded-proto", "https"),
+                ],
+                "203.0.113.9",
+                "http",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[
+                    ("x-forwarded-for", "198.51.100.1"),
+                    ("x-forwarded-proto", "https"),
+                ],
+                "198.51.100.1",
+                "https",
+            ),
+            // a spoofed leftmost entry, the proxy appended the address it saw
+            (
+                "10.0.0.1:4000",
+                &[("x-forwarded-for", "6.6.6.6, 198.51.100.1")],
+                "198.51.100.1",
+                "http",
+            ),
+            // the walk stops at the first hop that isn't one of our proxies
+            (
+                "10.0.0.1:4000",
+                &[("x-forwarded-for", "6.6.6.6, 198.51.100.1, 10.0.0.7")],
+                "198.51.100.1",
+                "http",
+            ),
+            // every hop trusted, the leftmost is the client
+            (
+                "10.0.0.1:4000",
+                &[("x-forwarded-for", "10.0.0.9, 10.0.0.7")],
+                "10.0.0.9",
+                "http",
+            ),
+            // repeated headers are one list
+            (
+                "10.0.0.1:4000",
+                &[
+                    ("x-forwarded-for", "6.6.6.6"),
+                    ("x-forwarded-for", "198.51.100.1"),
+                ],
+                "198.51.100.1",
+                "http",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[("forwarded", "for=198.51.100.1;proto=https")],
+                "198.51.100.1",
+                "https",
+            ),
+            // Forwarded wins over X-Forwarded-For
+            (
+                "10.0.0.1:4000",
+                &[
+                    ("forwarded", "for=198.51.100.1"),
+                    ("x-forwarded-for", "6.6.6.6"),
+                ],
+                "198.51.100.1",
+                "http",
+            ),
+            // ipv6 with brackets and ports
+            (
+                "10.0.0.1:4000",
+                &[("forwarded", "for=\"[2001:db8::1]:4711\";proto=https")],
+                "2001:db8::1",
+                "https",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[("forwarded", "for=\"[2001:db8::1]\"")],
+                "2001:db8::1",
+                "http",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[("x-forwarded-for", "[2001:db8::1]:4711")],
+                "2001:db8::1",
+                "http",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[("x-forwarded-for", "198.51.100.1:4711")],
+                "198.51.100.1",
+                "http",
+            ),
+            (
+                "[fd00::1]:4000",
+                &[("x-forwarded-for", "2001:db8::1, fd00::2")],
+                "2001:db8::1",
+                "http",
+            ),
+            // ipv4 mapped peers count as ipv4
+            (
+                "[::ffff:10.0.0.1]:4000",
+                &[("x-forwarded-for", "198.51.100.1")],
+                "198.51.100.1",
+                "http",
+            ),
+            // an unknown hop ends the walk at the proxy that reported it
+            (
+                "10.0.0.1:4000",
+                &[("forwarded", "for=6.6.6.6, for=unknown")],
+                "10.0.0.1",
+                "http",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[("forwarded", "for=6.6.6.6, for=unknown, for=10.0.0.7")],
+                "10.0.0.7",
+                "http",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[("forwarded", "for=6.6.6.6, for=_hidden")],
+                "10.0.0.1",
+                "http",
+            ),
+            (
+                "10.0.0.1:4000",
+                &[("x-forwarded-for", "6.6.6.6, garbage")],
+                "10.0.0.1",
+                "http",
+            ),
+            // only http and https are taken as the scheme
+            (
+                "10.0.0.1:4000",
+                &[("x-forwarded-proto", "gopher")],
+                "10.0.0.1",
+                "http",
+            ),
+        ];
+        for (peer, headers, ip, scheme) in cases {
+            assert_eq!(
+                client(peer, headers),
+                (ip.to_string(), scheme.to_string()),
+                "{} {:?}",
+                peer,
+                headers
+            );
+        }
+    }
+
+    #[test]
+    fn trusted_ranges() {
+        let trusted = [
+            "10.0.0.0/8".to_string(),
+            "192.0.2.1".to_string(),
+            "fd00::/8".to_string(),
+        ];
+        let cases = [
+            ("10.255.0.1", true),
+            ("11.0.0.1", false),
+            ("192.0.2.1", true),
+            ("192.0.2.2", false),
+            ("fd12::1", true),
+            ("fe80::1", false),
+        ];
+        for (ip, expected) in cases {
+            assert_eq!(
+                is_trusted(ip.parse().unwrap(), &trusted),
+                expected,
+                "{}",
+                ip
+            );
+        }
+    }
+}
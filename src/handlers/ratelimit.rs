@@ -0,0 +1,41 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+// requests per minute per client, 0 is unlimited (set from rate_limit, reloaded live)
+static RATE_LIMIT: AtomicU32 = AtomicU32::new(0);
+
+// start of the client's current one minute window and the requests counted in it
+static WINDOWS: LazyLock<Mutex<HashMap<IpAddr, (Instant, u32)>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+pub fn set_rate_limit(per_minute: u32) {
+    RATE_LIMIT.store(per_minute, Ordering::Relaxed);
+}
+
+/// false once the client has used up its requests for the current minute
+pub fn allow(ip: IpAddr) -> bool {
+    let limit = RATE_LIMIT.load(Ordering::Relaxed);
+    if limit == 0 {
+        return true;
+    }
+    let Ok(mut windows) = WINDOWS.lock() else {
+        return true;
+    };
+    let now = Instant::now();
+    // forget clients whose window is over, keeps the map small
+    if windows.len() > 4096 {
+        windows.retain(|_, (start, _)| now.duration_since(*start) < WINDOW);
+    }
+    let (start, count) = windows.entry(ip).or_insert((now, 0));
+    if now.duration_since(*start) >= WINDOW {
+        *start = now;
+        *count = 0;
+    }
+    *count += 1;
+    *count <= limit
+}
@@ -0,0 +1,383 @@
+use crate::handlers::login::refused;
+use http::StatusCode;
+use hyper::body::Bytes;
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::{Map, Value};
+
+const USERNAME_MAX: usize = 64;
+const PASSWORD_MIN: usize = 8;
+const PASSWORD_MAX: usize = 128;
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+    // totp or recovery code, needed once two-factor authentication is on
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+// the register form names its fields username-a, password-a and password-b
+#[derive(Debug, Deserialize)]
+pub struct RegisterRequest {
+    #[serde(alias = "username-a")]
+    pub username: String,
+    #[serde(alias = "password-a")]
+    pub password: String,
+    #[serde(alias = "password-b")]
+    pub confirm: String,
+    #[serde(default)]
+    pub invite: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PasswordRequest {
+    pub username: String,
+    pub password: String,
+    #[serde(rename = "password-new")]
+    pub new_password: String,
+    #[serde(default)]
+    pub code: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ResetRequest {
+    pub username: String,
+    pub token: String,
+    #[serde(rename = "password-new")]
+    pub new_password: String,
+}
+
+/// decodes a json or an application/x-www-form-urlencoded body (a form body can't start
+/// with a literal "{", browsers send it as %7B), a bad body is a 400
+pub fn decode<T: DeserializeOwned>(data: &Bytes) -> Result<T, Box<dyn std::error::Error>> {
+    let bad_request = |e: serde_json::Error| {
+        refused(
+            StatusCode::BAD_REQUEST,
+            format!("could not parse the request: {}", e),
+        )
+    };
+    if data.trim_ascii_start().starts_with(b"{") {
+        return serde_json::from_slice(data).map_err(bad_request);
+    }
+    // percent and + decoding, the last of repeated fields wins
+    let mut fields = Map::new();
+    for (key, value) in url::form_urlencoded::parse(data) {
+        fields.insert(key.into_owned(), Value::String(value.into_owned()));
+    }
+    serde_json::from_value(Value::Object(fields)).map_err(bad_request)
+}
+
+/// empty optional fields (like the code input left blank) count as missing
+pub fn filled(value: &Option<String>) -> Option<&str> {
+    value.as_deref().filter(|value| !value.is_empty())
+}
+
+// every problem with the field name, refused with a 400 when there are any
+fn refuse(problems: Vec<String>) -> Result<(), Box<dyn std::error::Error>> {
+    match problems.is_empty() {
+        true => Ok(()),
+        false => Err(refused(StatusCode::BAD_REQUEST, problems.join("; "))),
+    }
+}
+
+pub fn username_problem(username: &str) -> Option<String> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@');
+    if username.is_empty() || username.len() > USERNAME_MAX || !username.chars().all(allowed) {
+        return Some(format!(
+            "username: must be 1 to {} letters, digits, '.', '_', '-' or '@'",
+            USERNAME_MAX
+        ));
+    }
+    None
+}
+
+/// the rules for new passwords, existing passwords are only checked against the record
+pub fn password_problem(field: &str, password: &str) -> Option<String> {
+    let length = password.chars().count();
+    if !(PASSWORD_MIN..=PASSWORD_MAX).contains(&length) {
+        return Some(format!(
+            "{}: must be {} to {} characters",
+            field, PASSWORD_MIN, PASSWORD_MAX
+        ));
+    }
+    if password.trim() != password {
+        return Some(format!("{}: must not start or end with whitespace", field));
+    }
+    None
+}
+
+impl LoginRequest {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut problems = vec![];
+        if self.username.is_empty() {
+            problems.push("username: must be set".to_string());
+        }
+        if self.password.is_empty() {
+            problems.push("password: must be set".to_string());
+        }
+        refuse(problems)
+    }
+}
+
+impl RegisterRequest {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut problems = vec![];
+        problems.extend(username_problem(&self.username));
+        problems.extend(password_problem("password", &self.password));
+        if self.confirm != self.password {
+            problems.push("confirm: does not match the password".to_string());
+        }
+        refuse(problems)
+    }
+}
+
+impl PasswordRequest {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut problems = vec![];
+        if self.password.is_empty() {
+            problems.push("password: must be set".to_string());
+        }
+        problems.extend(password_problem("password-new", &self.new_password));
+        refuse(problems)
+    }
+}
+
+impl ResetRequest {
+    pub fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut problems = vec![];
+        if self.token.is_empty() {
+            problems.push("token: must be set".to_string());
+        }
+        problems.extend(password_problem("password-new", &self.new_password));
+        refuse(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::login::Refused;
+
+    // the message of a 400, anything else fails the test
+    fn bad_request(res: Result<impl std::fmt::Debug, Box<dyn std::error::Error>>) -> String {
+        let e = res.unwrap_err();
+        let refused = e.downcast_ref::<Refused>().expect("a refused request");
+        assert_eq!(refused.status, StatusCode::BAD_REQUEST);
+        refused.msg.clone()
+    }
+
+    fn login(body: &str) -> Result<LoginRequest, Box<dyn std::error::Error>> {
+        decode(&Bytes::from(body.to_string()))
+    }
+
+    #[test]
+    fn form_and_json_bodies() {
+        let cases = [
+            ("username=bob&password=secret", "bob", "secret", None),
+            // percent and + decoding
+            (
+                "username=bob%40example.com&password=a+b%2Bc%26d",
+                "bob@example.com",
+                "a b+c&d",
+                None,
+            ),
+            // the last of repeated fields wins
+            (
+                "username=eve&username=bob&password=secret",
+                "bob",
+                "secret",
+                None,
+            ),
+            // a form value can start with a brace
+            (
+                "username=bob&password=%7Bsecret%7D",
+                "bob",
+                "{secret}",
+                None,
+            ),
+            (
+                "username=bob&password=secret&code=123456",
+                "bob",
+                "secret",
+                Some("123456"),
+            ),
+            (
+                r#"{"username":"bob","password":"secret"}"#,
+                "bob",
+                "secret",
+                None,
+            ),
+            (
+                " \n{\"username\":\"bob\",\"password\":\"a+b\",\"code\":\"1\"}",
+                "bob",
+                "a+b",
+                Some("1"),
+            ),
+            // fields the request doesn't know are ignored
+            (
+                r#"{"username":"bob","password":"secret","remember":true}"#,
+                "bob",
+                "secret",
+                None,
+            ),
+        ];
+        for (body, username, password, code) in cases {
+            let req = login(body).unwrap();
+            assert_eq!(req.username, username, "{}", body);
+            assert_eq!(req.password, password, "{}", body);
+            assert_eq!(req.code.as_deref(), code, "{}", body);
+        }
+    }
+
+    #[test]
+    fn bad_bodies_are_400() {
+        let cases = [
+            ("username=bob", "missing field `password`"),
+            // a form encoded brace is a form, not json
+            (
+                "%7B%22username%22%3A%22bob%22%7D",
+                "missing field `username`",
+            ),
+            (
+                r#"{"username":"bob","password":1}"#,
+                "invalid type: integer `1`",
+            ),
+            (r#"{"username":"bob""#, "EOF while parsing an object"),
+            ("", "missing field `username`"),
+        ];
+        for (body, error) in cases {
+            let msg = bad_request(login(body));
+            assert!(msg.starts_with("could not parse the request: "), "{}", msg);
+            assert!(msg.contains(error), "{} for {}", msg, body);
+        }
+    }
+
+    #[test]
+    fn blank_code_is_missing() {
+        let req = login("username=bob&password=secret&code=").unwrap();
+        assert_eq!(req.code.as_deref(), Some(""));
+        assert_eq!(filled(&req.code), None);
+        assert_eq!(filled(&Some("1".to_string())), Some("1"));
+    }
+
+    #[test]
+    fn register_form_names_and_json_names() {
+        let bodies = [
+            "username-a=bob&password-a=long-secret&password-b=long-secret&invite=abc",
+            r#"{"username":"bob","password":"long-secret","confirm":"long-secret","invite":"abc"}"#,
+        ];
+        for body in bodies {
+            let req: RegisterRequest = decode(&Bytes::from(body)).unwrap();
+            assert_eq!(req.username, "bob");
+            assert_eq!(req.password, "long-secret");
+            assert_eq!(req.confirm, "long-secret");
+            assert_eq!(req.invite.as_deref(), Some("abc"));
+        }
+        let req: PasswordRequest = decode(&Bytes::from(
+            "username=bob&password=old&password-new=new-secret",
+        ))
+        .unwrap();
+        assert_eq!(req.new_password, "new-secret");
+        let req: ResetRequest = decode(&Bytes::from(
+            r#"{"username":"bob","token":"t","password-new":"x"}"#,
+        ))
+        .unwrap();
+        assert_eq!((req.token.as_str(), req.new_password.as_str()), ("t", "x"));
+    }
+
+    #[test]
+    fn username_rules() {
+        let problem = "username: must be 1 to 64 letters, digits, '.', '_', '-' or '@'";
+        for ok in ["bob", "bob.smith_2-x@example.com", &"a".repeat(64)] {
+            assert_eq!(username_problem(ok), None, "{}", ok);
+        }
+        for bad in [
+            "",
+            "bob smith",
+            "bob/x",
+            "bob<script>",
+            "bøb",
+            &"a".repeat(65),
+        ] {
+            assert_eq!(username_problem(bad).as_deref(), Some(problem), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn password_rules() {
+        for ok in ["12345678", "pass word", &"x".repeat(128), &"ü".repeat(8)] {
+            assert_eq!(password_problem("password", ok), None, "{}", ok);
+        }
+        let length = "password: must be 8 to 128 characters";
+        let whitespace = "password: must not start or end with whitespace";
+        let cases = [
+            ("", length),
+            ("1234567", length),
+            (&"x".repeat(129) as &str, length),
+            (" padded-password", whitespace),
+            ("padded-password\n", whitespace),
+        ];
+        for (bad, problem) in cases {
+            assert_eq!(password_problem("password", bad).as_deref(), Some(problem));
+        }
+    }
+
+    #[test]
+    fn validation_messages() {
+        let login = LoginRequest {
+            username: String::new(),
+            password: String::new(),
+            code: None,
+        };
+        assert_eq!(
+            bad_request(login.validate()),
+            "username: must be set; password: must be set"
+        );
+
+        let register = |username: &str, password: &str, confirm: &str| RegisterRequest {
+            username: username.to_string(),
+            password: password.to_string(),
+            confirm: confirm.to_string(),
+            invite: None,
+        };
+        assert!(
+            register("bob", "long-secret", "long-secret")
+                .validate()
+                .is_ok()
+        );
+        assert_eq!(
+            bad_request(register("bob", "long-secret", "long-secrets").validate()),
+            "confirm: does not match the password"
+        );
+        assert_eq!(
+            bad_request(register("bob smith", "short", "short").validate()),
+            "username: must be 1 to 64 letters, digits, '.', '_', '-' or '@'; \
+             password: must be 8 to 128 characters"
+        );
+
+        let password = |password: &str, new_password: &str| PasswordRequest {
+            username: "bob".to_string(),
+            password: password.to_string(),
+            new_password: new_password.to_string(),
+            code: None,
+        };
+        assert!(password("old", "new-secret").validate().is_ok());
+        assert_eq!(
+            bad_request(password("", " new-secret").validate()),
+            "password: must be set; password-new: must not start or end with whitespace"
+        );
+
+        let reset = |token: &str, new_password: &str| ResetRequest {
+            username: "bob".to_string(),
+            token: token.to_string(),
+            new_password: new_password.to_string(),
+        };
+        assert!(reset("t", "new-secret").validate().is_ok());
+        assert_eq!(
+            bad_request(reset("", "new").validate()),
+            "token: must be set; password-new: must be 8 to 128 characters"
+        );
+    }
+}
@@ -1,14 +1,27 @@
+use crate::certs::client::ClientUser;
+use crate::config::reload::reload;
 use crate::handlers::common::get_map_item;
-use crate::handlers::formdata::Form;
+use crate::handlers::formdata::{Form, category_options};
 use crate::handlers::interface::{InputformInterface, LoginformInterface, ViewformInterface};
-use crate::handlers::login::User;
+use crate::handlers::login::{Refused, User, read_user};
+use crate::handlers::markdown::render_html;
+use crate::handlers::oidc;
+use crate::handlers::proxy::RemoteClient;
+use crate::handlers::ratelimit::allow;
+use crate::handlers::session::{
+    cookie, create_session, end_session, session_cookie, session_user, set_cookie,
+};
 use crate::handlers::view::View;
+use crate::server::limits::Limits;
 use custom_logger as log;
+use http::uri::Authority;
 use http::{Method, Request, Response, StatusCode};
-use http_body_util::{BodyExt, Full};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
 use hyper::HeaderMap;
 use hyper::body::{Bytes, Incoming};
-use hyper::header::CONTENT_TYPE;
+use hyper::header::{
+    ACCEPT, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_TYPE, HOST, LOCATION, SET_COOKIE,
+};
 use std::fs;
 
 async fn get_index() -> Result<String, Box<dyn std::error::Error>> {
@@ -17,15 +30,264 @@ async fn get_index() -> Result<String, Box<dyn std::error::Error>> {
     Ok(html)
 }
 
+// documents are rendered to html unless ?raw=1 is set or the client accepts text/markdown
+fn wants_raw(req: &Request<Incoming>) -> bool {
+    url::form_urlencoded::parse(req.uri().query().unwrap_or_default().as_bytes())
+        .any(|(k, v)| k == "raw" && v == "1")
+        || req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("text/markdown"))
+}
+
+// plain http listener that sends every request to the https console
+pub async fn redirect_service(
+    req: Request<Incoming>,
+    https_port: String,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let mut response = Response::new(Full::default());
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Authority>().ok())
+        .map(|authority| authority.host().to_string())
+        .unwrap_or("localhost".to_string());
+    let port = match https_port.as_str() {
+        "443" => "".to_string(),
+        _ => format!(":{}", https_port),
+    };
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|p| p.as_str())
+        .unwrap_or("/");
+    let location = format!("https://{}{}{}", host, port, path);
+    // 308 keeps the method and body for anything other than GET/HEAD
+    *response.status_mut() = match req.method() {
+        &Method::GET | &Method::HEAD => StatusCode::MOVED_PERMANENTLY,
+        _ => StatusCode::PERMANENT_REDIRECT,
+    };
+    match location.parse() {
+        Ok(value) => {
+            response.headers_mut().insert(LOCATION, value);
+        }
+        Err(_) => {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+        }
+    }
+    Ok(response)
+}
+
 // ai webconsole
-pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>, hyper::Error> {
+// bodies over max_body_size are refused with a 413, a declared content-length is checked
+// before anything is read, a body that stops arriving for idle_timeout is refused with a 408
+async fn read_body(req: Request<Incoming>, limits: Limits) -> Result<Bytes, StatusCode> {
+    let max_body_size = limits.max_body_size;
+    let declared = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max_body_size) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    let limit = usize::try_from(max_body_size).unwrap_or(usize::MAX);
+    let mut body = Limited::new(req.into_body(), limit);
+    let mut data = vec![];
+    loop {
+        let frame = match tokio::time::timeout(limits.idle_timeout, body.frame()).await {
+            Ok(Some(Ok(frame))) => frame,
+            Ok(None) => return Ok(Bytes::from(data)),
+            Ok(Some(Err(e))) if e.is::<LengthLimitError>() => {
+                return Err(StatusCode::PAYLOAD_TOO_LARGE);
+            }
+            Ok(Some(Err(e))) => {
+                log::error!("failed to read request body: {}", e);
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            Err(_) => {
+                log::debug!("request body stalled for {:?}", limits.idle_timeout);
+                return Err(StatusCode::REQUEST_TIMEOUT);
+            }
+        };
+        if let Ok(chunk) = frame.into_data() {
+            data.extend_from_slice(&chunk);
+        }
+    }
+}
+
+// admins are client certificate users listed in admin_users, or certificate and
+// session users with the admin role (admin_users only counts for certificates)
+async fn is_admin(user: Option<ClientUser>, session: Option<String>) -> bool {
+    let user = user.map(|ClientUser(user)| user);
+    let listed = user.as_ref().is_some_and(|user| {
+        get_map_item("admin_users".to_string())
+            .map(|admins| admins.split(',').any(|admin| admin == user))
+            .unwrap_or(false)
+    });
+    if listed {
+        return true;
+    }
+    let session_user = match session {
+        Some(id) => session_user(&id).await.ok().flatten(),
+        None => None,
+    };
+    for user in user.into_iter().chain(session_user) {
+        if matches!(read_user(&user).await, Ok(Some(ud)) if ud.role == "admin" && !ud.disabled && !ud.pending)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+// GET /oidc/login (off to the provider), /oidc/callback (back with a session cookie)
+// and /session (the user of the session cookie, and whether single sign-on is offered)
+async fn session_service(
+    path: String,
+    query: String,
+    cookie: Option<String>,
+    state: Option<String>,
+    secure: bool,
+) -> Response<Full<Bytes>> {
     let mut response = Response::new(Full::default());
-    log::debug!("request uri {}", req.uri());
+    if path == "/webconsole/oidc/login" {
+        if !oidc::enabled() {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            *response.body_mut() = Full::from("openid connect is not configured");
+            return response;
+        }
+        match oidc::authorization_url().await {
+            Ok((url, state)) => {
+                *response.status_mut() = StatusCode::FOUND;
+                if let Ok(location) = url.parse() {
+                    response.headers_mut().insert(LOCATION, location);
+                }
+                if let Ok(cookie) = oidc::state_cookie(&state, secure).parse() {
+                    response.headers_mut().insert(SET_COOKIE, cookie);
+                }
+            }
+            Err(e) => {
+                log::error!("openid connect login: {}", e);
+                // too many logins in progress is a 503, the provider failing a 502
+                *response.status_mut() = match e.downcast_ref::<Refused>() {
+                    Some(refused) => refused.status,
+                    None => StatusCode::BAD_GATEWAY,
+                };
+                *response.body_mut() = Full::from(e.to_string());
+            }
+        }
+        return response;
+    }
+    if path == "/webconsole/oidc/callback" {
+        // the state cookie is done with whatever the outcome
+        if let Ok(cookie) = oidc::state_cookie("", secure).parse() {
+            response.headers_mut().append(SET_COOKIE, cookie);
+        }
+        let user = match oidc::callback(&query, state.as_deref()).await {
+            Ok(user) => user,
+            Err(e) => {
+                log::warn!("openid connect login refused: {}", e);
+                *response.status_mut() = StatusCode::FORBIDDEN;
+                *response.body_mut() = Full::from(e.to_string());
+                return response;
+            }
+        };
+        match create_session(&user).await {
+            Ok(id) => {
+                *response.status_mut() = StatusCode::FOUND;
+                response
+                    .headers_mut()
+                    .insert(LOCATION, "/webconsole/index.html".parse().unwrap());
+                if let Ok(cookie) = set_cookie(&id, secure).parse() {
+                    response.headers_mut().append(SET_COOKIE, cookie);
+                }
+            }
+            Err(e) => {
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                *response.body_mut() = Full::from(e.to_string());
+            }
+        }
+        return response;
+    }
+    let user = match cookie {
+        Some(id) => session_user(&id).await.unwrap_or_else(|e| {
+            log::error!("session lookup: {}", e);
+            None
+        }),
+        None => None,
+    };
+    let body = serde_json::json!({"user": user, "sso": oidc::enabled()});
+    *response.status_mut() = StatusCode::OK;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    *response.body_mut() = Full::from(body.to_string());
+    response
+}
+
+// refused logins and registrations carry their status, anything else is a 500
+fn refused_status(e: &(dyn std::error::Error + 'static)) -> StatusCode {
+    match e.downcast_ref::<Refused>() {
+        Some(refused) => refused.status,
+        None => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+pub async fn ai_service(
+    req: Request<Incoming>,
+    limits: Limits,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    let mut response = Response::new(Full::default());
+    match req.extensions().get::<RemoteClient>() {
+        Some(remote) => log::debug!(
+            "request uri {} from {} ({})",
+            req.uri(),
+            remote.ip,
+            remote.scheme
+        ),
+        None => log::debug!("request uri {}", req.uri()),
+    }
+    let limited = req
+        .extensions()
+        .get::<RemoteClient>()
+        .filter(|remote| !allow(remote.ip));
+    if let Some(remote) = limited {
+        log::warn!("rate limit reached for {}", remote.ip);
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        *response.body_mut() = Full::from("too many requests");
+        return Ok(response);
+    }
+    // routes match on the exact path or a path prefix ending in '/', the rest is the key
     let req_uri = req.uri().to_string();
+    let path = req.uri().path().to_string();
+    let route = |prefix: &str| path.starts_with(prefix);
     match req.method() {
         &Method::GET => {
+            if matches!(
+                path.as_str(),
+                "/webconsole/oidc/login" | "/webconsole/oidc/callback" | "/webconsole/session"
+            ) {
+                let secure = req
+                    .extensions()
+                    .get::<RemoteClient>()
+                    .is_some_and(|remote| remote.scheme == "https");
+                return Ok(session_service(
+                    path.clone(),
+                    req.uri().query().unwrap_or_default().to_string(),
+                    session_cookie(req.headers()),
+                    cookie(req.headers(), oidc::STATE_COOKIE),
+                    secure,
+                )
+                .await);
+            }
             // GET /index
-            if req_uri.contains("index") {
+            if matches!(
+                path.as_str(),
+                "/webconsole/index" | "/webconsole/index.html"
+            ) {
                 let result = get_index().await;
                 match result {
                     Ok(html) => {
@@ -38,8 +300,13 @@ pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>,
                     }
                 }
             }
+            // GET /categories (options for the category select)
+            if path == "/webconsole/categories" {
+                *response.status_mut() = StatusCode::OK;
+                *response.body_mut() = Full::from(category_options(""));
+            }
             // GET /formdata/{key}
-            if req_uri.contains("formdata") {
+            if route("/webconsole/formdata/") {
                 let fd_res = Form::get_formdata(req_uri.clone()).await;
                 match fd_res {
                     Ok(html) => {
@@ -53,13 +320,53 @@ pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>,
                 }
             }
             // GET /document for viewing /view/{key}
-            if req_uri.contains("view") {
-                let res = View::get_formdata(req_uri).await;
+            if route("/webconsole/view/") {
+                let (res, content_type) = match wants_raw(&req) {
+                    true => (View::get_formdata(req_uri.clone()).await, "text/markdown"),
+                    false => (
+                        View::render_formdata(req_uri.clone()).await,
+                        "text/html; charset=utf-8",
+                    ),
+                };
+                match res {
+                    Ok(doc) => {
+                        let mut headers = HeaderMap::new();
+                        headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
+                        *response.headers_mut() = headers;
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(doc);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // GET /history/{key}
+            if route("/webconsole/history/") {
+                let res = View::history_formdata(req_uri.clone()).await;
+                match res {
+                    Ok(html) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(html);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // GET /revision/{key}/{revision}
+            if route("/webconsole/revision/") {
+                let res = View::revision_formdata(req_uri.clone()).await;
                 match res {
                     Ok(doc) => {
+                        let (doc, content_type) = match wants_raw(&req) {
+                            true => (doc, "text/markdown"),
+                            false => (render_html(&doc), "text/html; charset=utf-8"),
+                        };
                         let mut headers = HeaderMap::new();
-                        //headers.insert(ACCEPT, "*".parse().unwrap());
-                        headers.insert(CONTENT_TYPE, "text/markdown".parse().unwrap());
+                        headers.insert(CONTENT_TYPE, content_type.parse().unwrap());
                         *response.headers_mut() = headers;
                         *response.status_mut() = StatusCode::OK;
                         *response.body_mut() = Full::from(doc);
@@ -70,40 +377,178 @@ pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>,
                     }
                 }
             }
+            // GET /diff/{key}/{from}/{to}
+            if route("/webconsole/diff/") {
+                let res = View::diff_formdata(req_uri.clone()).await;
+                match res {
+                    Ok(html) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(html);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // GET /export/{key}?format=html|text|md and /bundle?prefix=&from=&to=&source=
+            if route("/webconsole/export/") || path == "/webconsole/bundle" {
+                let res = match route("/webconsole/export/") {
+                    true => View::export_formdata(req_uri.clone()).await,
+                    false => View::bundle_formdata(req_uri.clone()).await,
+                };
+                match res {
+                    Ok(export) => {
+                        let mut headers = HeaderMap::new();
+                        headers.insert(CONTENT_TYPE, export.content_type.parse().unwrap());
+                        headers.insert(
+                            CONTENT_DISPOSITION,
+                            format!("attachment; filename=\"{}\"", export.filename)
+                                .parse()
+                                .unwrap(),
+                        );
+                        *response.headers_mut() = headers;
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(export.body);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
         }
 
         &Method::POST => {
-            let data = req.into_body().collect().await?.to_bytes();
-            // POST /login
-            if req_uri.contains("login") {
-                let res = User::get_formdata(data.clone()).await;
+            // POST /admin/reload (re-reads the config, applies the live settings)
+            if path == "/webconsole/admin/reload" {
+                let user = req.extensions().get::<ClientUser>().cloned();
+                if !is_admin(user, session_cookie(req.headers())).await {
+                    *response.status_mut() = StatusCode::FORBIDDEN;
+                    *response.body_mut() = Full::from("admin only");
+                    return Ok(response);
+                }
+                match reload() {
+                    Ok(summary) => {
+                        log::info!("config reload (admin): {}", summary);
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(summary);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+                return Ok(response);
+            }
+            // POST /logout (ends the session of the cookie)
+            if path == "/webconsole/logout" {
+                let ended = match session_cookie(req.headers()) {
+                    Some(id) => end_session(&id).await,
+                    None => Ok(()),
+                };
+                if let Err(e) = ended {
+                    log::error!("logout: {}", e);
+                }
+                let secure = req
+                    .extensions()
+                    .get::<RemoteClient>()
+                    .is_some_and(|remote| remote.scheme == "https");
+                if let Ok(cookie) = set_cookie("", secure).parse() {
+                    response.headers_mut().insert(SET_COOKIE, cookie);
+                }
+                *response.status_mut() = StatusCode::OK;
+                *response.body_mut() = Full::from("logged out");
+                return Ok(response);
+            }
+            let client_user = req.extensions().get::<ClientUser>().cloned();
+            // failed logins are counted per client ip
+            let ip = req
+                .extensions()
+                .get::<RemoteClient>()
+                .map(|remote| remote.ip.to_string())
+                .unwrap_or("unknown".to_string());
+            let data = match read_body(req, limits).await {
+                Ok(data) => data,
+                Err(status) => {
+                    *response.status_mut() = status;
+                    return Ok(response);
+                }
+            };
+            // POST /login (a verified client certificate replaces the password)
+            if path == "/webconsole/login" {
+                let res = match client_user {
+                    Some(ClientUser(user)) => User::cert_formdata(user).await,
+                    None => User::get_formdata(data.clone(), ip.clone()).await,
+                };
                 match res {
                     Ok(value) => {
                         *response.status_mut() = StatusCode::OK;
                         *response.body_mut() = Full::from(value);
                     }
+                    // locked out clients (429) and missing totp codes (401)
                     Err(e) => {
-                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.status_mut() = refused_status(e.as_ref());
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // POST /totp/enroll, /totp/confirm, /totp/disable (two-factor authentication)
+            if route("/webconsole/totp/") {
+                match User::totp_formdata(req_uri.clone(), data.clone(), ip.clone()).await {
+                    Ok(value) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(value);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = refused_status(e.as_ref());
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // POST /password (change, needs the current password)
+            if path == "/webconsole/password" {
+                match User::password_formdata(data.clone(), ip.clone()).await {
+                    Ok(value) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(value);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = refused_status(e.as_ref());
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // POST /reset (set a new password with a reset token)
+            if path == "/webconsole/reset" {
+                match User::reset_formdata(data.clone()).await {
+                    Ok(value) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(value);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = refused_status(e.as_ref());
                         *response.body_mut() = Full::from(e.to_string());
                     }
                 }
             }
             // POST /register
-            if req_uri.contains("register") {
+            if path == "/webconsole/register" {
                 let result = User::save_formdata(data.clone()).await;
                 match result {
                     Ok(value) => {
                         *response.status_mut() = StatusCode::OK;
                         *response.body_mut() = Full::from(value);
                     }
+                    // taken names (409) and closed registration (403)
                     Err(e) => {
-                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.status_mut() = refused_status(e.as_ref());
                         *response.body_mut() = Full::from(e.to_string());
                     }
                 }
             }
             // POST /formdata
-            if req_uri.contains("formdata") {
+            if path == "/webconsole/formdata" {
                 let res = Form::save_formdata(data.clone()).await;
                 match res {
                     Ok(fd) => {
@@ -117,7 +562,7 @@ pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>,
                 }
             }
             // POST /search
-            if req_uri.contains("search") {
+            if path == "/webconsole/search" {
                 let result = Form::search_formdata(data.clone()).await;
                 match result {
                     Ok(html) => {
@@ -131,7 +576,7 @@ pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>,
                 }
             }
             // POST /view
-            if req_uri.contains("view") {
+            if path == "/webconsole/view" {
                 let result = View::save_formdata(data.clone()).await;
                 match result {
                     Ok(res) => {
@@ -144,10 +589,52 @@ pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>,
                     }
                 }
             }
+            // POST /restore/{key}/{revision}
+            if route("/webconsole/restore/") {
+                let result = View::restore_formdata(req_uri.clone(), data.clone()).await;
+                match result {
+                    Ok(res) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(res);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // POST /documents (list with prefix and date filters)
+            if path == "/webconsole/documents" {
+                let result = View::search_formdata(data.clone()).await;
+                match result {
+                    Ok(html) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(html);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
+            // POST /rename
+            if path == "/webconsole/rename" {
+                let result = View::rename_formdata(data.clone()).await;
+                match result {
+                    Ok(res) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(res);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
         }
         &Method::DELETE => {
             // DELETE /formdata/{key}
-            if req_uri.contains("formdata") {
+            if route("/webconsole/formdata/") {
                 log::debug!("deleting formdata");
                 let fd_res = Form::delete_formdata(req_uri.clone()).await;
                 match fd_res {
@@ -161,6 +648,21 @@ pub async fn ai_service(req: Request<Incoming>) -> Result<Response<Full<Bytes>>,
                     }
                 }
             }
+            // DELETE /documents/{key}
+            if route("/webconsole/documents/") {
+                log::debug!("deleting document");
+                let res = View::delete_formdata(req_uri.clone()).await;
+                match res {
+                    Ok(msg) => {
+                        *response.status_mut() = StatusCode::OK;
+                        *response.body_mut() = Full::from(msg);
+                    }
+                    Err(e) => {
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Full::from(e.to_string());
+                    }
+                }
+            }
         }
 
         _ => {
@@ -0,0 +1,122 @@
+use crate::handlers::common::{get_error, get_map_item, get_opts};
+use crate::handlers::login::{read_user, token_hash};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::Local;
+use custom_logger as log;
+use http::HeaderMap;
+use http::header::COOKIE;
+use hyper::body::Bytes;
+use serde_derive::{Deserialize, Serialize};
+
+pub const SESSION_COOKIE: &str = "webconsole_session";
+
+// kept under the sha256 of the cookie value, the tree alone doesn't give a session
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    user: String,
+    expires: i64,
+}
+
+// session_ttl seconds (default 8 hours)
+fn session_ttl() -> i64 {
+    get_map_item("session_ttl".to_string())
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(28800)
+}
+
+/// starts a session for the user, returns the cookie value
+pub async fn create_session(user: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let mut bytes = [0u8; 32];
+    aws_lc_rs::rand::fill(&mut bytes).map_err(|_| "unable to generate a session id")?;
+    let id = URL_SAFE_NO_PAD.encode(bytes);
+    let now = Local::now().timestamp();
+    let session = Session {
+        user: user.to_string(),
+        expires: now + session_ttl(),
+    };
+    let tree = get_opts("sessions".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    // expired sessions are dropped when a new one starts
+    let mut expired = vec![];
+    for x in txn.range(b"", &[0xff], None)? {
+        let (key, value) = x.map_err(|e| get_error(e.to_string()))?;
+        let old = value.and_then(|value| serde_json::from_slice::<Session>(&value).ok());
+        if old.is_none_or(|old| old.expires <= now) {
+            expired.push(key.to_vec());
+        }
+    }
+    for key in expired {
+        txn.delete(&key).map_err(|e| get_error(e.to_string()))?;
+    }
+    let key = Bytes::from(token_hash(&id));
+    let value = Bytes::from(serde_json::to_string(&session)?);
+    txn.set(&key, &value)
+        .map_err(|e| get_error(e.to_string()))?;
+    txn.commit().await?;
+    tree.close().await?;
+    log::debug!("session started for user {}", user);
+    Ok(id)
+}
+
+/// the user of a live session, disabled users lose their sessions
+pub async fn session_user(id: &str) -> Result<Option<String>, Box<dyn std::error::Error>> {
+    let tree = get_opts("sessions".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let key = Bytes::from(token_hash(id));
+    let res = txn.get(&key).map_err(|e| get_error(e.to_string()))?;
+    txn.commit().await?;
+    tree.close().await?;
+    let Some(val) = res else {
+        return Ok(None);
+    };
+    let session: Session = serde_json::from_slice(&val).map_err(|e| get_error(e.to_string()))?;
+    if session.expires <= Local::now().timestamp() {
+        return Ok(None);
+    }
+    match read_user(&session.user).await? {
+        Some(ud) if !ud.disabled && !ud.pending => Ok(Some(session.user)),
+        _ => Ok(None),
+    }
+}
+
+pub async fn end_session(id: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let tree = get_opts("sessions".to_string())?;
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let key = Bytes::from(token_hash(id));
+    txn.delete(&key).map_err(|e| get_error(e.to_string()))?;
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(())
+}
+
+/// the session id from the Cookie header
+pub fn session_cookie(headers: &HeaderMap) -> Option<String> {
+    cookie(headers, SESSION_COOKIE)
+}
+
+/// a cookie value from the Cookie header
+pub fn cookie(headers: &HeaderMap, cookie: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == cookie)
+        .map(|(_, value)| value.to_string())
+}
+
+/// Set-Cookie value, an empty id clears the cookie
+pub fn set_cookie(id: &str, secure: bool) -> String {
+    let max_age = match id.is_empty() {
+        true => 0,
+        false => session_ttl(),
+    };
+    let secure = if secure { "; Secure" } else { "" };
+    format!(
+        "{}={}; Path=/webconsole; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        SESSION_COOKIE, id, max_age, secure
+    )
+}
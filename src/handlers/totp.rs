@@ -0,0 +1,226 @@
+use aws_lc_rs::hmac;
+
+// rfc 6238 defaults, what authenticator apps expect
+const STEP: i64 = 30;
+const DIGITS: u32 = 6;
+const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// a random 160 bit secret, base32 encoded (no padding)
+pub fn generate_secret() -> Result<String, Box<dyn std::error::Error>> {
+    let mut bytes = [0u8; 20];
+    aws_lc_rs::rand::fill(&mut bytes).map_err(|_| "unable to generate a totp secret")?;
+    Ok(base32_encode(&bytes))
+}
+
+/// the otpauth uri authenticator apps read from the qr code
+pub fn provisioning_uri(issuer: &str, user: &str, secret: &str) -> String {
+    // the label is a path, spaces are %20 there ("+" is already escaped as %2B)
+    let escape = |s: &str| {
+        url::form_urlencoded::byte_serialize(s.as_bytes())
+            .collect::<String>()
+            .replace('+', "%20")
+    };
+    let (issuer, user) = (escape(issuer), escape(user));
+    format!(
+        "otpauth://totp/{}:{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        issuer, user, secret, issuer, DIGITS, STEP
+    )
+}
+
+/// single use codes for when the authenticator is lost, shown once as xxxx-xxxx
+pub fn recovery_codes(count: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let mut codes = vec![];
+    for _ in 0..count {
+        let mut bytes = [0u8; 5];
+        aws_lc_rs::rand::fill(&mut bytes).map_err(|_| "unable to generate a recovery code")?;
+        let code = base32_encode(&bytes).to_lowercase();
+        codes.push(format!("{}-{}", &code[..4], &code[4..]));
+    }
+    Ok(codes)
+}
+
+/// codes are compared without dashes, spaces and case
+pub fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| *c != '-' && *c != ' ')
+        .collect::<String>()
+        .to_uppercase()
+}
+
+pub fn current_step(now: i64) -> i64 {
+    now / STEP
+}
+
+fn code_at(key: &[u8], step: i64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, key);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let hash = tag.as_ref();
+    // dynamic truncation (rfc 4226 section 5.3)
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+    format!(
+        "{:0width$}",
+        value % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// the time step the code belongs to, one step of clock drift either way is accepted
+/// and steps up to last (already used) are refused
+pub fn verify(secret: &str, code: &str, now: i64, last: i64) -> Option<i64> {
+    let key = base32_decode(secret)?;
+    let step = current_step(now);
+    (step - 1..=step + 1)
+        .filter(|s| *s > last)
+        .find(|s| code_at(&key, *s) == code)
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(5) {
+        let mut buf = [0u8; 5];
+        buf[..chunk.len()].copy_from_slice(chunk);
+        let bits = u64::from_be_bytes([0, 0, 0, buf[0], buf[1], buf[2], buf[3], buf[4]]);
+        let chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..chars {
+            out.push(ALPHABET[((bits >> (35 - i * 5)) & 0x1f) as usize] as char);
+        }
+    }
+    out
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let mut bits: u64 = 0;
+    let mut count = 0;
+    for c in data.trim_end_matches('=').bytes() {
+        let value = ALPHABET.iter().position(|a| *a == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u64;
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            out.push((bits >> count) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the RFC 6238 appendix B sha1 seed, base32 encoded
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn rfc6238_sha1_vectors() {
+        // appendix B lists 8 digits, the 6 digit codes are the last 6
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+        let key = base32_decode(SECRET).unwrap();
+        assert_eq!(key, b"12345678901234567890");
+        for (time, code) in vectors {
+            assert_eq!(code_at(&key, current_step(time)), code, "time {}", time);
+            assert_eq!(verify(SECRET, code, time, 0), Some(current_step(time)));
+        }
+    }
+
+    #[test]
+    fn base32_rfc4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+        for (data, encoded) in vectors {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), data.as_bytes());
+        }
+        // padding and lower case from other tools are accepted
+        assert_eq!(base32_decode("mzxw6yq=").unwrap(), b"foob");
+        assert_eq!(base32_decode("MZXW6!"), None);
+    }
+
+    #[test]
+    fn base32_round_trip() {
+        let mut bytes = [0u8; 64];
+        aws_lc_rs::rand::fill(&mut bytes).unwrap();
+        for len in 0..=bytes.len() {
+            let encoded = base32_encode(&bytes[..len]);
+            assert_eq!(base32_decode(&encoded).unwrap(), &bytes[..len]);
+        }
+        let secret = generate_secret().unwrap();
+        assert_eq!(secret.len(), 32);
+        assert_eq!(base32_decode(&secret).unwrap().len(), 20);
+    }
+
+    #[test]
+    fn one_step_of_drift_either_way() {
+        let key = base32_decode(SECRET).unwrap();
+        let now = 1234567890;
+        let step = current_step(now);
+        for drift in [-1, 0, 1] {
+            let code = code_at(&key, step + drift);
+            assert_eq!(verify(SECRET, &code, now, 0), Some(step + drift));
+        }
+        for drift in [-2, 2] {
+            let code = code_at(&key, step + drift);
+            assert_eq!(verify(SECRET, &code, now, 0), None);
+        }
+        assert_eq!(verify("not base32!", "005924", now, 0), None);
+    }
+
+    #[test]
+    fn used_codes_are_refused() {
+        let now = 1234567890;
+        let step = verify(SECRET, "005924", now, 0).unwrap();
+        // the same code again, or an older one inside the drift window
+        assert_eq!(verify(SECRET, "005924", now, step), None);
+        assert_eq!(verify(SECRET, "005924", now + 10, step), None);
+        let key = base32_decode(SECRET).unwrap();
+        let previous = code_at(&key, step - 1);
+        assert_eq!(verify(SECRET, &previous, now, step), None);
+        // the next code is still good
+        let next = code_at(&key, step + 1);
+        assert_eq!(verify(SECRET, &next, now + STEP, step), Some(step + 1));
+    }
+
+    #[test]
+    fn recovery_codes_and_normalize() {
+        let codes = recovery_codes(10).unwrap();
+        assert_eq!(codes.len(), 10);
+        for code in &codes {
+            assert_eq!(code.len(), 9);
+            assert_eq!(&code[4..5], "-");
+            assert_eq!(normalize(code), code.replace('-', "").to_uppercase());
+        }
+        assert_eq!(normalize(" abcd-efgh "), "ABCDEFGH");
+    }
+
+    #[test]
+    fn provisioning_uri_escapes_the_label() {
+        assert_eq!(
+            provisioning_uri("AI Console", "bob+1@example.com", SECRET),
+            format!(
+                "otpauth://totp/AI%20Console:bob%2B1%40example.com?secret={}\
+                 &issuer=AI%20Console&algorithm=SHA1&digits=6&period=30",
+                SECRET
+            )
+        );
+    }
+}
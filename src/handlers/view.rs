@@ -1,49 +1,540 @@
 use crate::handlers::common::{get_error, get_opts};
+use crate::handlers::formdata::db_read as db_read_formdata;
 use crate::handlers::interface::ViewformInterface;
+use crate::handlers::markdown::{render_html, render_page, render_text};
 use async_trait::async_trait;
+use chrono::Local;
 use custom_logger as log;
 use hyper::body::Bytes;
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, percent_decode_str, utf8_percent_encode};
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use similar::{ChangeTag, TextDiff};
+use std::collections::HashMap;
+use std::io::{Cursor, Write};
+use tokio::sync::Mutex;
+use zip::ZipWriter;
+use zip::write::SimpleFileOptions;
+
+// a save reads the meta, keeps the replaced document as a revision (dropped again when the
+// document can't be stored), writes the document and then the meta (last, once the rest is
+// stored), writes are serialized so no revision is lost
+static DOCUMENT_WRITE: Mutex<()> = Mutex::const_new(());
+
+// everything but the unreserved characters is encoded in a name path segment
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct View {
     pub name: String,
     pub document: String,
+    #[serde(default)]
+    pub author: Option<String>,
+    #[serde(default)]
+    pub source: Option<DocumentSource>,
+}
+
+/// links a generated document back to the form (job) and prompt that produced it
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DocumentSource {
+    pub key: String,
+    pub db: String,
+    pub category: String,
+    pub model: String,
+    pub prompt_hash: String,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocumentMeta {
+    pub name: String,
+    pub updated: String,
+    pub size: usize,
+    #[serde(default)]
+    pub revision: u32,
+    #[serde(default)]
+    pub author: String,
+    #[serde(default)]
+    pub created: String,
+    #[serde(default)]
+    pub source: DocumentSource,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Revision {
+    pub name: String,
+    pub revision: u32,
+    pub author: String,
+    pub updated: String,
+    pub document: String,
+    #[serde(default)]
+    pub source: DocumentSource,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocumentSearch {
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub from: String,
+    #[serde(default)]
+    pub to: String,
+    #[serde(default)]
+    pub source: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocumentRename {
+    pub name: String,
+    pub new_name: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DocumentRestore {
+    #[serde(default)]
+    pub author: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Export {
+    pub filename: String,
+    pub content_type: String,
+    pub body: Vec<u8>,
 }
 
 #[async_trait]
 impl ViewformInterface for View {
     async fn get_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>> {
-        let params: Vec<&str> = req_uri.split("/").collect();
-        let key = params.last().unwrap().to_string();
+        let params = route_params(&req_uri, "view", 1)?;
+        let key = params[0].clone();
         log::debug!("[get_fromdata] view key {}", key);
         let result = db_read(key).await?;
         Ok(result)
     }
 
+    async fn render_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>> {
+        let document = Self::get_formdata(req_uri).await?;
+        Ok(render_html(&document))
+    }
+
     async fn save_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>> {
-        let result = db_upsert(data).await?;
+        let view: View = serde_json::from_slice(&data)?;
+        check_name(&view.name)?;
+        let result = db_upsert(view).await?;
         Ok(result)
     }
+
+    async fn search_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>> {
+        let ds: DocumentSearch = serde_json::from_slice(&data)?;
+        let result = db_read_search(ds).await?;
+        let html = render_results_html(result);
+        Ok(html)
+    }
+
+    async fn delete_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>> {
+        let params = route_params(&req_uri, "documents", 1)?;
+        log::debug!("[delete_formdata] params {:?}", params);
+        let key = params[0].clone();
+        db_delete(key.clone()).await?;
+        Ok(format!("document {} deleted successfully", key))
+    }
+
+    async fn rename_formdata(data: Bytes) -> Result<String, Box<dyn std::error::Error>> {
+        let dr: DocumentRename = serde_json::from_slice(&data)?;
+        if dr.new_name.is_empty() {
+            return Err(get_error("new document name is empty".to_string()));
+        }
+        check_name(&dr.new_name)?;
+        db_rename(dr.name.clone(), dr.new_name.clone()).await?;
+        Ok(format!(
+            "document {} renamed to {} successfully",
+            dr.name, dr.new_name
+        ))
+    }
+
+    async fn history_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>> {
+        let params = route_params(&req_uri, "history", 1)?;
+        log::debug!("[history_formdata] params {:?}", params);
+        let key = params[0].clone();
+        let meta_res = db_read_meta(key.clone()).await?;
+        let meta = match meta_res {
+            Some(meta) => meta,
+            // documents saved before metadata was tracked
+            None => {
+                let document = db_read(key.clone()).await?;
+                DocumentMeta {
+                    name: key.clone(),
+                    updated: "".to_string(),
+                    size: document.len(),
+                    revision: 1,
+                    author: "".to_string(),
+                    created: "".to_string(),
+                    source: DocumentSource::default(),
+                }
+            }
+        };
+        let mut revisions = db_read_revisions(key).await?;
+        revisions.reverse();
+        let html = render_history_html(meta, revisions);
+        Ok(html)
+    }
+
+    async fn revision_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>> {
+        let params = route_params(&req_uri, "revision", 2)?;
+        log::debug!("[revision_formdata] params {:?}", params);
+        let key = params[0].clone();
+        let revision: u32 = params[1].parse()?;
+        let result = get_revision(key, revision).await?;
+        Ok(result.document)
+    }
+
+    async fn diff_formdata(req_uri: String) -> Result<String, Box<dyn std::error::Error>> {
+        let params = route_params(&req_uri, "diff", 3)?;
+        log::debug!("[diff_formdata] params {:?}", params);
+        let key = params[0].clone();
+        let from: u32 = params[1].parse()?;
+        let to: u32 = params[2].parse()?;
+        let old = get_revision(key.clone(), from).await?;
+        let new = get_revision(key, to).await?;
+        let html = render_diff_html(old, new);
+        Ok(html)
+    }
+
+    async fn restore_formdata(
+        req_uri: String,
+        data: Bytes,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let params = route_params(&req_uri, "restore", 2)?;
+        log::debug!("[restore_formdata] params {:?}", params);
+        let key = params[0].clone();
+        let revision: u32 = params[1].parse()?;
+        let author = match data.is_empty() {
+            true => None,
+            false => serde_json::from_slice::<DocumentRestore>(&data)?.author,
+        };
+        let rev = get_revision(key.clone(), revision).await?;
+        let view = View {
+            name: key.clone(),
+            document: rev.document,
+            author,
+            source: Some(rev.source),
+        };
+        db_upsert(view).await?;
+        Ok(format!(
+            "document {} restored from revision {} successfully",
+            key, revision
+        ))
+    }
+
+    async fn export_formdata(req_uri: String) -> Result<Export, Box<dyn std::error::Error>> {
+        let query = req_uri.split_once("?").map(|(_, q)| q).unwrap_or("");
+        let params = route_params(&req_uri, "export", 1)?;
+        log::debug!("[export_formdata] params {:?}", params);
+        let key = params[0].clone();
+        let query_params = parse_query(query);
+        let format = query_params.get("format").map(|f| f.as_str());
+        let document = db_read(key.clone()).await?;
+        let export = match format.unwrap_or("html") {
+            "html" => Export {
+                filename: format!("{}.html", file_name(&key)),
+                content_type: "text/html; charset=utf-8".to_string(),
+                body: render_page(&key, &document).into_bytes(),
+            },
+            "text" => Export {
+                filename: format!("{}.txt", file_name(&key)),
+                content_type: "text/plain; charset=utf-8".to_string(),
+                body: render_text(&document).into_bytes(),
+            },
+            "md" => Export {
+                filename: format!("{}.md", file_name(&key)),
+                content_type: "text/markdown; charset=utf-8".to_string(),
+                body: document.into_bytes(),
+            },
+            other => {
+                return Err(get_error(format!(
+                    "export format {} not available (use html, text or md)",
+                    other
+                )));
+            }
+        };
+        Ok(export)
+    }
+
+    async fn bundle_formdata(req_uri: String) -> Result<Export, Box<dyn std::error::Error>> {
+        let query = req_uri.split_once("?").map(|(_, q)| q).unwrap_or("");
+        let query_params = parse_query(query);
+        let get = |name: &str| query_params.get(name).cloned().unwrap_or_default();
+        let ds = DocumentSearch {
+            prefix: get("prefix"),
+            from: get("from"),
+            to: get("to"),
+            source: get("source"),
+        };
+        let docs = db_read_search(ds).await?;
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default();
+        for doc in docs.iter() {
+            let document = db_read(doc.name.clone()).await?;
+            zip.start_file(format!("{}.md", file_name(&doc.name)), options)?;
+            zip.write_all(document.as_bytes())?;
+        }
+        zip.start_file("index.json", options)?;
+        zip.write_all(serde_json::to_string_pretty(&docs)?.as_bytes())?;
+        let body = zip.finish()?.into_inner();
+        log::debug!("[bundle_formdata] {} documents exported", docs.len());
+        Ok(Export {
+            filename: format!("documents-{}.zip", Local::now().format("%Y%m%d%H%M%S")),
+            content_type: "application/zip".to_string(),
+            body,
+        })
+    }
 }
 
-#[allow(dead_code)]
-async fn db_upsert(data: Bytes) -> Result<String, Box<dyn std::error::Error>> {
+async fn db_upsert(view: View) -> Result<String, Box<dyn std::error::Error>> {
+    let _write = DOCUMENT_WRITE.lock().await;
+    let meta = db_read_meta(view.name.clone()).await?;
+    let source = match view.source {
+        Some(source) => resolve_source(source).await?,
+        None => meta.as_ref().map(|m| m.source.clone()).unwrap_or_default(),
+    };
+    let now = Local::now().format("%Y%m%d%H%M%S").to_string();
+    let created = match meta.as_ref() {
+        Some(meta) if !meta.created.is_empty() => meta.created.clone(),
+        Some(meta) => meta.updated.clone(),
+        None => now.clone(),
+    };
     let tree = get_opts("documents".to_string())?;
     // start transaction
     let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
     txn.set_durability(surrealkv::Durability::Immediate);
-    let view: View = serde_json::from_slice(&data)?;
     let b_key = Bytes::from(view.name.clone());
+    // keep the document being replaced as a revision before overwriting it
+    let previous = txn.get(&b_key).map_err(|e| get_error(e.to_string()))?;
+    let kept = match previous {
+        Some(val) => {
+            // documents saved before metadata was tracked start at revision 1
+            let (revision, author, updated, previous_source) = match meta {
+                Some(meta) => (meta.revision.max(1), meta.author, meta.updated, meta.source),
+                None => (1, "".to_string(), "".to_string(), DocumentSource::default()),
+            };
+            db_insert_revision(Revision {
+                name: view.name.clone(),
+                revision,
+                author,
+                updated,
+                document: String::from_utf8(val.to_vec())?,
+                source: previous_source,
+            })
+            .await?;
+            Some(revision)
+        }
+        None => None,
+    };
+    let revision = kept.map(|r| r + 1).unwrap_or(1);
+    let size = view.document.len();
     let b_value = Bytes::from(view.document);
     log::debug!("[db_upsert] document with key {}", view.name);
+    // commit transaction, a revision kept for a document that isn't replaced is dropped again
+    let stored = match txn.set(&b_key, &b_value) {
+        Ok(()) => txn.commit().await.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = stored {
+        if let Some(kept) = kept {
+            db_delete_revision(&view.name, kept).await?;
+        }
+        return Err(get_error(e));
+    }
+    tree.close().await?;
+    let meta = DocumentMeta {
+        name: view.name.clone(),
+        updated: now,
+        size,
+        revision,
+        author: view.author.unwrap_or_default(),
+        created,
+        source,
+    };
+    db_upsert_meta(meta).await?;
+    let msg = format!("document {} created/updated successfully", view.name);
+    Ok(msg)
+}
+
+// fills in category and prompt hash from the form record when the producer only sent its key
+async fn resolve_source(
+    mut source: DocumentSource,
+) -> Result<DocumentSource, Box<dyn std::error::Error>> {
+    if source.key.is_empty() || (!source.category.is_empty() && !source.prompt_hash.is_empty()) {
+        return Ok(source);
+    }
+    let dbs = match source.db.as_str() {
+        "" => vec!["formdata", "queue", "archive"],
+        db => vec![db],
+    };
+    for db in dbs {
+        let fd = db_read_formdata(source.key.clone(), db.to_string()).await?;
+        if fd.key.is_some() {
+            if source.category.is_empty() {
+                source.category = fd.category;
+            }
+            if source.prompt_hash.is_empty() {
+                source.prompt_hash = format!("{:x}", Sha256::digest(fd.prompt.as_bytes()));
+            }
+            source.db = db.to_string();
+            break;
+        }
+    }
+    Ok(source)
+}
+
+async fn db_upsert_meta(meta: DocumentMeta) -> Result<(), Box<dyn std::error::Error>> {
+    let tree = get_opts("documents-meta".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    txn.set_durability(surrealkv::Durability::Immediate);
+    let b_key = Bytes::from(meta.name.clone());
+    let json_data = serde_json::to_string(&meta)?;
+    log::debug!("[db_upsert_meta] meta {}", json_data);
+    let b_value = Bytes::from(json_data);
     txn.set(&b_key, &b_value)
         .map_err(|e| get_error(e.to_string()))?;
     // commit transaction
     txn.commit().await?;
     tree.close().await?;
-    let msg = format!("document {} created/updated successfully", view.name);
-    Ok(msg)
+    Ok(())
+}
+
+async fn db_read_meta(key: String) -> Result<Option<DocumentMeta>, Box<dyn std::error::Error>> {
+    let tree = get_opts("documents-meta".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let b_key = Bytes::from(key.clone());
+    let res = txn.get(&b_key).map_err(|e| get_error(e.to_string()))?;
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    match res {
+        Some(val) => {
+            let meta: DocumentMeta = serde_json::from_slice(&val)?;
+            Ok(Some(meta))
+        }
+        None => Ok(None),
+    }
+}
+
+async fn db_insert_revision(rev: Revision) -> Result<(), Box<dyn std::error::Error>> {
+    let tree = get_opts("documents-history".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    txn.set_durability(surrealkv::Durability::Immediate);
+    let b_key = Bytes::from(revision_key(&rev.name, rev.revision));
+    let json_data = serde_json::to_string(&rev)?;
+    log::debug!(
+        "[db_insert_revision] {} revision {}",
+        rev.name,
+        rev.revision
+    );
+    let b_value = Bytes::from(json_data);
+    txn.set(&b_key, &b_value)
+        .map_err(|e| get_error(e.to_string()))?;
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(())
+}
+
+async fn db_delete_revision(key: &str, revision: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let tree = get_opts("documents-history".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    txn.set_durability(surrealkv::Durability::Immediate);
+    let b_key = Bytes::from(revision_key(key, revision));
+    log::debug!("[db_delete_revision] {} revision {}", key, revision);
+    txn.delete(&b_key).map_err(|e| get_error(e.to_string()))?;
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(())
+}
+
+async fn db_read_revisions(key: String) -> Result<Vec<Revision>, Box<dyn std::error::Error>> {
+    let mut vec_revs: Vec<Revision> = vec![];
+    let prefix = format!("{}/", key);
+    let (start, end) = prefix_range(&prefix);
+    let tree = get_opts("documents-history".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let results = txn.range(&start, &end, None)?;
+    for x in results.into_iter() {
+        let kv = x.as_ref().unwrap();
+        if !is_revision_of(&key, &kv.0) {
+            continue;
+        }
+        let value = kv.1.as_ref().unwrap().to_vec();
+        let rev: Revision = serde_json::from_slice(&value)?;
+        vec_revs.push(rev);
+    }
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(vec_revs)
+}
+
+async fn get_revision(key: String, revision: u32) -> Result<Revision, Box<dyn std::error::Error>> {
+    let meta = db_read_meta(key.clone()).await?;
+    let current = meta.as_ref().map(|m| m.revision.max(1)).unwrap_or(1);
+    if revision == current {
+        let document = db_read(key.clone()).await?;
+        let (author, updated, source) = match meta {
+            Some(meta) => (meta.author, meta.updated, meta.source),
+            None => ("".to_string(), "".to_string(), DocumentSource::default()),
+        };
+        return Ok(Revision {
+            name: key,
+            revision,
+            author,
+            updated,
+            document,
+            source,
+        });
+    }
+    let tree = get_opts("documents-history".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let b_key = Bytes::from(revision_key(&key, revision));
+    let res = txn.get(&b_key).map_err(|e| get_error(e.to_string()))?;
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    match res {
+        Some(val) => {
+            let rev: Revision = serde_json::from_slice(&val)?;
+            Ok(rev)
+        }
+        None => {
+            let msg = format!("no revision {} found for document {}", revision, key);
+            log::error!("{}", msg);
+            Err(get_error(msg))
+        }
+    }
+}
+
+async fn db_contains(db: &str, key: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    let tree = get_opts(db.to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let b_key = Bytes::from(key.to_string());
+    let res = txn.get(&b_key).map_err(|e| get_error(e.to_string()))?;
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(res.is_some())
 }
 
 async fn db_read(key: String) -> Result<String, Box<dyn std::error::Error>> {
@@ -67,3 +558,472 @@ async fn db_read(key: String) -> Result<String, Box<dyn std::error::Error>> {
         }
     }
 }
+
+async fn db_delete(key: String) -> Result<(), Box<dyn std::error::Error>> {
+    let _write = DOCUMENT_WRITE.lock().await;
+    for db in ["documents", "documents-meta"] {
+        let tree = get_opts(db.to_string())?;
+        // start transaction
+        let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+        txn.set_durability(surrealkv::Durability::Immediate);
+        let b_key = Bytes::from(key.clone());
+        log::debug!("[db_delete] {} key {}", db, key);
+        txn.delete(&b_key).map_err(|e| get_error(e.to_string()))?;
+        // commit transaction
+        txn.commit().await?;
+        tree.close().await?;
+    }
+    let revisions = db_read_revisions(key.clone()).await?;
+    let tree = get_opts("documents-history".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    txn.set_durability(surrealkv::Durability::Immediate);
+    for rev in revisions {
+        let b_key = Bytes::from(revision_key(&key, rev.revision));
+        txn.delete(&b_key).map_err(|e| get_error(e.to_string()))?;
+    }
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(())
+}
+
+// held for the whole rename, the new name is checked in every tree before anything is written
+async fn db_rename(key: String, new_key: String) -> Result<(), Box<dyn std::error::Error>> {
+    let _write = DOCUMENT_WRITE.lock().await;
+    if !db_contains("documents", &key).await? {
+        return Err(get_error(format!("no document found with key {}", key)));
+    }
+    if db_contains("documents", &new_key).await?
+        || db_contains("documents-meta", &new_key).await?
+        || !db_read_revisions(new_key.clone()).await?.is_empty()
+    {
+        return Err(get_error(format!("document {} already exists", new_key)));
+    }
+    for db in ["documents", "documents-meta"] {
+        let tree = get_opts(db.to_string())?;
+        // start transaction
+        let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+        txn.set_durability(surrealkv::Durability::Immediate);
+        let b_key = Bytes::from(key.clone());
+        let b_new_key = Bytes::from(new_key.clone());
+        let res = txn.get(&b_key).map_err(|e| get_error(e.to_string()))?;
+        // documents saved before metadata was tracked have no meta entry
+        if let Some(val) = res {
+            let b_value = match db {
+                "documents-meta" => {
+                    let mut meta: DocumentMeta = serde_json::from_slice(&val)?;
+                    meta.name = new_key.clone();
+                    Bytes::from(serde_json::to_string(&meta)?)
+                }
+                _ => Bytes::from(val.to_vec()),
+            };
+            log::debug!("[db_rename] {} key {} to {}", db, key, new_key);
+            txn.set(&b_new_key, &b_value)
+                .map_err(|e| get_error(e.to_string()))?;
+            txn.delete(&b_key).map_err(|e| get_error(e.to_string()))?;
+        }
+        // commit transaction
+        txn.commit().await?;
+        tree.close().await?;
+    }
+    let revisions = db_read_revisions(key.clone()).await?;
+    let tree = get_opts("documents-history".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    txn.set_durability(surrealkv::Durability::Immediate);
+    for mut rev in revisions {
+        let b_key = Bytes::from(revision_key(&key, rev.revision));
+        let b_new_key = Bytes::from(revision_key(&new_key, rev.revision));
+        rev.name = new_key.clone();
+        let b_value = Bytes::from(serde_json::to_string(&rev)?);
+        txn.set(&b_new_key, &b_value)
+            .map_err(|e| get_error(e.to_string()))?;
+        txn.delete(&b_key).map_err(|e| get_error(e.to_string()))?;
+    }
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+    Ok(())
+}
+
+async fn db_read_search(
+    ds: DocumentSearch,
+) -> Result<Vec<DocumentMeta>, Box<dyn std::error::Error>> {
+    let mut vec_docs: Vec<DocumentMeta> = vec![];
+    log::debug!("[db_read_search] prefix {}", ds.prefix);
+    let (start, end) = prefix_range(&ds.prefix);
+    let tree = get_opts("documents".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    let results = txn.range(&start, &end, None)?;
+    for x in results.into_iter() {
+        let kv = x.as_ref().unwrap();
+        let key = String::from_utf8(kv.0.to_vec())?;
+        let size = kv.1.as_ref().map(|v| v.len()).unwrap_or(0);
+        vec_docs.push(DocumentMeta {
+            name: key,
+            updated: "".to_string(),
+            size,
+            revision: 1,
+            author: "".to_string(),
+            created: "".to_string(),
+            source: DocumentSource::default(),
+        });
+    }
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+
+    let tree = get_opts("documents-meta".to_string())?;
+    // start transaction
+    let mut txn = tree.begin().map_err(|e| get_error(e.to_string()))?;
+    for doc in vec_docs.iter_mut() {
+        let b_key = Bytes::from(doc.name.clone());
+        if let Some(val) = txn.get(&b_key).map_err(|e| get_error(e.to_string()))? {
+            let meta: DocumentMeta = serde_json::from_slice(&val)?;
+            doc.updated = meta.updated;
+            doc.revision = meta.revision.max(1);
+            doc.author = meta.author;
+            doc.created = meta.created;
+            doc.source = meta.source;
+        }
+    }
+    // commit transaction
+    txn.commit().await?;
+    tree.close().await?;
+
+    let from = match ds.from.as_str() {
+        "" => "".to_string(),
+        _ => format!("{}000000", ds.from.replace("-", "")),
+    };
+    let to = match ds.to.as_str() {
+        "" => "".to_string(),
+        _ => format!("{}235959", ds.to.replace("-", "")),
+    };
+    vec_docs.retain(|doc| {
+        (from.is_empty() || doc.updated >= from)
+            && (to.is_empty() || doc.updated <= to)
+            && (ds.source.is_empty() || doc.source.key == ds.source)
+    });
+    Ok(vec_docs)
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect()
+}
+
+// keeps exported file names inside the bundle and out of the header quoting
+fn file_name(key: &str) -> String {
+    key.chars()
+        .map(|c| match c {
+            '/' | '\\' | '"' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// names are percent encoded as a path segment of the view, history and export urls
+fn url_name(name: &str) -> String {
+    utf8_percent_encode(name, SEGMENT).to_string()
+}
+
+// the decoded segments that follow /webconsole/{route}/ in the request path
+fn route_params(
+    req_uri: &str,
+    route: &str,
+    count: usize,
+) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+    let path = req_uri.split("?").next().unwrap_or_default();
+    let rest = path
+        .strip_prefix(&format!("/webconsole/{}/", route))
+        .unwrap_or_default();
+    let params = rest
+        .split("/")
+        .map(|p| percent_decode_str(p).decode_utf8().map(|p| p.to_string()))
+        .collect::<Result<Vec<String>, _>>()?;
+    if params.len() != count || params.iter().any(|p| p.is_empty()) {
+        return Err(get_error("uri parameters are incorrrect".to_string()));
+    }
+    Ok(params)
+}
+
+// the name is a path segment of the view, history and export urls, revisions are stored under name/nnnnnnnn
+fn check_name(name: &str) -> Result<(), Box<dyn std::error::Error>> {
+    if name.is_empty() || name.contains('/') {
+        return Err(get_error(format!(
+            "document name '{}' must be set and can't contain '/'",
+            name
+        )));
+    }
+    Ok(())
+}
+
+// revision numbers are zero padded so a range scan returns them in order
+fn revision_key(key: &str, revision: u32) -> String {
+    format!("{}/{:08}", key, revision)
+}
+
+// the prefix of a also matches the revisions of a/b (saved before names with '/' were refused)
+fn is_revision_of(key: &str, stored: &[u8]) -> bool {
+    stored
+        .strip_prefix(format!("{}/", key).as_bytes())
+        .is_some_and(|suffix| suffix.len() == 8 && suffix.iter().all(|b| b.is_ascii_digit()))
+}
+
+fn prefix_range(prefix: &str) -> (Vec<u8>, Vec<u8>) {
+    let start = prefix.as_bytes().to_vec();
+    let mut end = start.clone();
+    // 0xff never appears in utf-8 so this bounds every key with the prefix
+    end.push(0xff);
+    (start, end)
+}
+
+fn format_timestamp(ts: &str) -> String {
+    match chrono::NaiveDateTime::parse_from_str(ts, "%Y%m%d%H%M%S") {
+        Ok(dt) => dt.format("%Y-%m-%d %H:%M:%S").to_string(),
+        Err(_) => "".to_string(),
+    }
+}
+
+fn render_results_html(rows: Vec<DocumentMeta>) -> String {
+    let mut html = String::new();
+    for doc in rows.iter() {
+        // names come from save and rename, escaped everywhere they are written
+        let name = ammonia::clean_text(&doc.name);
+        let url = url_name(&doc.name);
+        // jump back to the form that produced the document, the source comes from the POST /view body
+        let source = match doc.source.key.as_str() {
+            "" => "".to_string(),
+            key => {
+                let key = ammonia::clean_text(key);
+                format!(
+                    "<i id=\"icon-formdata\" class=\"fa fa-link\" hx-get=\"/webconsole/formdata/{}/{}\" hx-target=\"#inputForm\" hx-trigger=\"click\"></i>&nbsp;{}",
+                    key,
+                    ammonia::clean_text(&doc.source.db),
+                    key
+                )
+            }
+        };
+        let tokens = doc.source.prompt_tokens + doc.source.completion_tokens;
+        let html_row = format!(
+            "
+        <tr>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td><i id=\"icon-view\" class=\"fa fa-eye\" hx-get=\"/webconsole/view/{}\" hx-trigger=\"click\" hx-target=\"#view-results\"></i>&nbsp;&nbsp;&nbsp;<i id=\"icon-history\" class=\"fa fa-history\" hx-get=\"/webconsole/history/{}\" hx-trigger=\"click\" hx-target=\"#history-results\"></i>&nbsp;&nbsp;&nbsp;<a href=\"/webconsole/export/{}?format=html\" style=\"color: inherit;\"><i class=\"fa fa-download\"></i></a>&nbsp;&nbsp;&nbsp;<i id=\"icon-rename\" class=\"fa fa-pencil\" data-name=\"{}\" onclick=\"renameDocument(this.dataset.name);return false;\"></i>&nbsp;&nbsp;&nbsp;<i id=\"icon-document-delete\" class=\"fa fa-trash-o\" hx-delete=\"/webconsole/documents/{}\" hx-trigger=\"click\" hx-target=\"#response\"></i></td>
+        </tr>",
+            name,
+            format_timestamp(&doc.updated),
+            doc.size,
+            doc.revision,
+            source,
+            ammonia::clean_text(&doc.source.category),
+            ammonia::clean_text(&doc.source.model),
+            tokens,
+            url,
+            url,
+            url,
+            name,
+            url
+        );
+        html.push_str(&html_row);
+    }
+    html
+}
+
+fn render_history_html(meta: DocumentMeta, revisions: Vec<Revision>) -> String {
+    let current = meta.revision.max(1);
+    let name = url_name(&meta.name);
+    let mut html = format!(
+        "
+        <tr>
+            <td>{} (current)</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td><i id=\"icon-view\" class=\"fa fa-eye\" hx-get=\"/webconsole/revision/{}/{}\" hx-trigger=\"click\" hx-target=\"#view-results\"></i></td>
+        </tr>",
+        current,
+        ammonia::clean_text(&meta.author),
+        format_timestamp(&meta.updated),
+        name,
+        current
+    );
+    for rev in revisions.iter() {
+        let html_row = format!(
+            "
+        <tr>
+            <td>{}</td>
+            <td>{}</td>
+            <td>{}</td>
+            <td><i id=\"icon-view\" class=\"fa fa-eye\" hx-get=\"/webconsole/revision/{}/{}\" hx-trigger=\"click\" hx-target=\"#view-results\"></i>&nbsp;&nbsp;&nbsp;<i id=\"icon-diff\" class=\"fa fa-exchange\" hx-get=\"/webconsole/diff/{}/{}/{}\" hx-trigger=\"click\" hx-target=\"#view-results\"></i>&nbsp;&nbsp;&nbsp;<i id=\"icon-restore\" class=\"fa fa-undo\" hx-post=\"/webconsole/restore/{}/{}\" hx-ext=\"json-enc\" hx-vals=\"js:{{author: document.getElementById('credentials').value}}\" hx-trigger=\"click\" hx-target=\"#response\"></i></td>
+        </tr>",
+            rev.revision,
+            ammonia::clean_text(&rev.author),
+            format_timestamp(&rev.updated),
+            name,
+            rev.revision,
+            name,
+            rev.revision,
+            current,
+            name,
+            rev.revision
+        );
+        html.push_str(&html_row);
+    }
+    html
+}
+
+fn render_diff_html(old: Revision, new: Revision) -> String {
+    let diff = TextDiff::from_lines(&old.document, &new.document);
+    let mut html = format!(
+        "<h3>{} revision {} &rarr; {}</h3>\n<pre class=\"diff\">",
+        ammonia::clean_text(&old.name),
+        old.revision,
+        new.revision
+    );
+    for change in diff.iter_all_changes() {
+        let (sign, style) = match change.tag() {
+            ChangeTag::Delete => ("-", "color: #ff7b72;"),
+            ChangeTag::Insert => ("+", "color: #7ee787;"),
+            ChangeTag::Equal => (" ", "color: #c0c0c0;"),
+        };
+        html.push_str(&format!(
+            "<span style=\"{}\">{}{}</span>",
+            style,
+            sign,
+            ammonia::clean_text(&change.to_string())
+        ));
+    }
+    html.push_str("</pre>");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_round_trip_through_the_url() {
+        let names = [
+            "notes",
+            "q3 report",
+            "what?",
+            "#1 & <b>",
+            "100%",
+            "a+b",
+            "résumé 日本",
+        ];
+        for name in names {
+            let url = url_name(name);
+            assert!(
+                url.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-._~%".contains(c)),
+                "{} encodes to {}",
+                name,
+                url
+            );
+            let uri = format!("/webconsole/view/{}?raw=1", url);
+            assert_eq!(route_params(&uri, "view", 1).unwrap(), vec![name]);
+        }
+    }
+
+    fn revision(name: &str, revision: u32, document: &str) -> Revision {
+        Revision {
+            name: name.to_string(),
+            revision,
+            author: "".to_string(),
+            updated: "".to_string(),
+            document: document.to_string(),
+            source: DocumentSource::default(),
+        }
+    }
+
+    #[test]
+    fn revision_keys_sort_in_revision_order() {
+        assert_eq!(revision_key("notes", 1), "notes/00000001");
+        assert_eq!(revision_key("notes", 99999999), "notes/99999999");
+        let revisions = [1, 2, 9, 10, 11, 99, 100, 101, 1000];
+        let mut keys: Vec<String> = revisions
+            .iter()
+            .map(|r| revision_key("notes", *r))
+            .collect();
+        keys.sort();
+        let sorted: Vec<String> = revisions
+            .iter()
+            .map(|r| revision_key("notes", *r))
+            .collect();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn revisions_belong_to_their_document_only() {
+        let cases = [
+            ("notes", "notes/00000001", true),
+            ("notes", "notes/00000100", true),
+            ("notes", "notes-old/00000001", false),
+            ("notes", "notes/old/00000001", false),
+            ("notes", "notes/1", false),
+            ("notes", "notes/0000000x", false),
+            ("notes", "notes/000000001", false),
+            ("notes-old", "notes/00000001", false),
+        ];
+        for (key, stored, expected) in cases {
+            assert_eq!(
+                is_revision_of(key, stored.as_bytes()),
+                expected,
+                "{} {}",
+                key,
+                stored
+            );
+        }
+        // the range scan of notes/ never reaches notes-old/ ('-' sorts before '/')
+        let (start, end) = prefix_range("notes/");
+        let other = b"notes-old/00000001".to_vec();
+        assert!(other < start || other > end);
+    }
+
+    #[test]
+    fn diff_output_is_escaped() {
+        let old = revision("<b>notes</b>", 1, "keep\n<script>alert(1)</script>\n");
+        let new = revision("<b>notes</b>", 2, "keep\n<img src=x onerror=alert(1)>\n");
+        let html = render_diff_html(old, new);
+        assert!(html.starts_with("<h3>&lt;b&gt;notes&lt;&#47;b&gt; revision 1 &rarr; 2</h3>"));
+        assert!(html.contains("> keep&#10;</span>"));
+        assert!(html.contains(">-&lt;script&gt;alert(1)&lt;&#47;script&gt;&#10;</span>"));
+        assert!(html.contains(">+&lt;img&#32;src&#61;x&#32;onerror&#61;alert(1)&gt;&#10;</span>"));
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("<img"));
+    }
+
+    #[test]
+    fn route_params_count_and_prefix() {
+        let params = route_params("/webconsole/diff/a%20b/1/3", "diff", 3).unwrap();
+        assert_eq!(params, vec!["a b", "1", "3"]);
+        let params = route_params("/webconsole/revision/notes/2", "revision", 2).unwrap();
+        assert_eq!(params, vec!["notes", "2"]);
+        let refused = [
+            ("/webconsole/view/", "view", 1),
+            ("/webconsole/view/a/b", "view", 1),
+            ("/webconsole/revision/notes", "revision", 2),
+            ("/webconsole/revision//2", "revision", 2),
+            // the key follows the route, not some other path holding it
+            ("/webconsole/preview/notes", "view", 1),
+            ("/other/webconsole/view/notes", "view", 1),
+            // not utf-8 once decoded
+            ("/webconsole/view/%ff", "view", 1),
+        ];
+        for (uri, route, count) in refused {
+            assert!(route_params(uri, route, count).is_err(), "{}", uri);
+        }
+    }
+}
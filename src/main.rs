@@ -1,24 +1,35 @@
-use crate::certs::controller::{CertificateInterface, ImplCertificateInterface, error};
-use crate::cli::schema::Cli;
+use crate::certs::acme::watch_acme;
+use crate::certs::client::client_verifier;
+use crate::certs::controller::{CertificateInterface, ImplCertificateInterface};
+use crate::certs::resolver::{
+    ACME_TLS_ALPN, CertResolver, load_certified_key, load_host_keys, watch_certificates,
+};
+use crate::cli::schema::{Cli, Commands, ConfigCommands, UserCommands};
+use crate::config::format::convert;
+use crate::config::overrides::redacted;
 use crate::config::process::{ConfigInterface, ImplConfigInterface, Parameters};
-use crate::handlers::service::ai_service;
+use crate::config::reload::{categories, level, registration, watch_config};
+use crate::config::validate::validate;
+use crate::server::connection::{Serve, accept_loop, shutdown_signal};
+use crate::server::limits::Limits;
+use crate::server::listener::{Listener, bind_listeners, bind_redirect, systemd_listeners};
 use clap::Parser;
 use custom_logger as log;
-use hyper::service::service_fn;
-use hyper_util::rt::{TokioExecutor, TokioIo};
-use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::GracefulShutdown;
 use rustls::ServerConfig;
 use std::collections::HashMap;
-use std::net::{Ipv4Addr, SocketAddr};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::sync::{Semaphore, watch};
 use tokio_rustls::TlsAcceptor;
 
 mod certs;
 mod cli;
 mod config;
 mod handlers;
+mod server;
 
 // used for lookup in read mode only
 static MAP_LOOKUP: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
@@ -26,7 +37,12 @@ static MAP_LOOKUP: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
 fn main() {
     // Serve an jwt auth service over HTTPS, with proper error handling.
     let args = Cli::parse();
+    let sets = args.sets();
     let config = args.config;
+    let name = match &config {
+        Some(config) => format!("config {}", config),
+        None => "config".to_string(),
+    };
     let impl_config = ImplConfigInterface {};
 
     // setup logging
@@ -35,18 +51,70 @@ fn main() {
         .init()
         .expect("log should initialize");
 
-    // read and parse config
-    let params = impl_config.read(config);
+    if let Some(Commands::Config {
+        command:
+            ConfigCommands::Convert {
+                input,
+                output,
+                force,
+            },
+    }) = &args.command
+    {
+        if let Err(e) = convert(input, output, *force) {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+        log::info!("converted {} to {}", input, output);
+        return;
+    }
+
+    // read and parse config (defaults < file < env < --set), then validate it
+    let params = impl_config.read(config.clone(), &sets);
     if params.is_err() {
         log::error!("{}", params.err().unwrap());
         std::process::exit(1);
     }
 
-    let level = match params.as_ref().unwrap().log_level.as_str() {
-        "debug" => log::LevelFilter::Debug,
-        "trace" => log::LevelFilter::Trace,
-        &_ => log::LevelFilter::Info,
-    };
+    // user management works on the login tree, only db_path is needed
+    if let Some(Commands::User { command }) = args.command {
+        let db_path = params.as_ref().unwrap().db_path.clone();
+        if db_path.is_empty() {
+            log::error!("{} is invalid:\n  db_path: must be set", name);
+            std::process::exit(1);
+        }
+        let mut hm = HashMap::from([("db_path".to_string(), db_path)]);
+        // user link defaults to the configured provider
+        if let Some(oidc) = &params.as_ref().unwrap().oidc {
+            hm.insert("oidc_issuer".to_string(), oidc.issuer.clone());
+        }
+        *MAP_LOOKUP.lock().unwrap() = Some(hm);
+        match run_user(command) {
+            Ok(out) => println!("{}", out),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let problems = validate(params.as_ref().unwrap());
+    if args.print_config {
+        let effective = redacted(params.as_ref().unwrap());
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&effective).unwrap_or_default()
+        );
+    }
+    if !problems.is_empty() {
+        log::error!("{} is invalid:\n  {}", name, problems.join("\n  "));
+        std::process::exit(1);
+    }
+    if args.print_config {
+        return;
+    }
+
+    let level = level(&params.as_ref().unwrap().log_level);
 
     log::info!("application : {}", env!("CARGO_PKG_NAME"));
     log::info!("author      : {}", env!("CARGO_PKG_AUTHORS"));
@@ -71,54 +139,258 @@ fn main() {
         "static_dir".to_string(),
         params.as_ref().unwrap().static_dir.to_string(),
     );
+    hm.insert(
+        "categories".to_string(),
+        categories(params.as_ref().unwrap()),
+    );
+    hm.insert(
+        "registration".to_string(),
+        registration(params.as_ref().unwrap()),
+    );
+    hm.insert("name".to_string(), params.as_ref().unwrap().name.clone());
+    hm.insert(
+        "require_2fa".to_string(),
+        params
+            .as_ref()
+            .unwrap()
+            .require_2fa
+            .unwrap_or(false)
+            .to_string(),
+    );
+    hm.insert(
+        "session_ttl".to_string(),
+        params
+            .as_ref()
+            .unwrap()
+            .session_ttl
+            .unwrap_or(28800)
+            .to_string(),
+    );
+    hm.insert(
+        "lockout_attempts".to_string(),
+        params
+            .as_ref()
+            .unwrap()
+            .lockout_attempts
+            .unwrap_or(5)
+            .to_string(),
+    );
+    hm.insert(
+        "lockout_account_attempts".to_string(),
+        params
+            .as_ref()
+            .unwrap()
+            .lockout_account_attempts
+            .unwrap_or(20)
+            .to_string(),
+    );
+    hm.insert(
+        "lockout_duration".to_string(),
+        params
+            .as_ref()
+            .unwrap()
+            .lockout_duration
+            .unwrap_or(900)
+            .to_string(),
+    );
+    hm.insert(
+        "admin_users".to_string(),
+        params
+            .as_ref()
+            .unwrap()
+            .admin_users
+            .clone()
+            .unwrap_or_default()
+            .join(","),
+    );
 
     *MAP_LOOKUP.lock().unwrap() = Some(hm.clone());
+    handlers::oidc::init(params.as_ref().unwrap().oidc.clone());
+    config::reload::init(config.clone(), sets, params.as_ref().unwrap());
 
-    if let Err(e) = run_server(params.unwrap()) {
+    if let Err(e) = run_server(params.unwrap(), config) {
         log::error!("{}", e);
         std::process::exit(1);
     }
 }
 
 #[tokio::main]
-async fn run_server(params: Parameters) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let addr = SocketAddr::new(Ipv4Addr::new(0, 0, 0, 0).into(), params.port.parse()?);
+async fn run_user(command: UserCommands) -> Result<String, Box<dyn std::error::Error>> {
+    cli::users::run(command).await
+}
+
+#[tokio::main]
+async fn run_server(
+    params: Parameters,
+    config: Option<String>,
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    // Apply log level, static dir, categories and rate limit changes without a restart.
+    tokio::spawn(watch_config(
+        config,
+        params.config_reload_interval.unwrap_or(10),
+    ));
+    let limits = Limits::new(&params);
+    let trusted_proxies = Arc::new(params.trusted_proxies.unwrap_or_default());
+    // Sockets passed by systemd replace the configured listeners.
+    let mut listeners = systemd_listeners()?;
+    if listeners.is_empty() {
+        listeners = bind_listeners(params.listen, &params.port)?;
+    }
+    // Plain http, tls is terminated by a proxy in front of the console.
+    if !params.tls.unwrap_or(true) {
+        serve(
+            listeners,
+            None,
+            vec![],
+            trusted_proxies,
+            limits,
+            &params.name,
+            params.shutdown_timeout.unwrap_or(30),
+        )
+        .await;
+        return Ok(());
+    }
+    // Redirect plain http visitors to the https console.
+    let mut redirects = vec![];
+    if let Some(redirect_port) = params.redirect_port {
+        for redirect in bind_redirect(&listeners, &redirect_port)? {
+            redirects.push((redirect, params.port.clone()));
+        }
+        if redirects.is_empty() {
+            log::warn!(
+                "redirect_port {} needs a tcp listener, not redirecting",
+                redirect_port
+            );
+        }
+    }
     let certs_dir = params.certs_dir.unwrap_or("".to_string()).to_string();
     log::debug!("certs directory {}", certs_dir);
-    let impl_certs = ImplCertificateInterface::new(params.cert_mode, Some(certs_dir));
-    // Load public certificate.
-    let certs = impl_certs.get_public_cert().await?;
-    // Load private key.
-    let key = impl_certs.get_private_cert().await?;
-    log::info!("starting {} on https://{}", params.name, addr);
-    // Create a TCP listener via tokio.
-    let incoming = TcpListener::bind(&addr).await?;
+    let sans = params.cert_sans.unwrap_or_default();
+    let impl_certs = ImplCertificateInterface::new(
+        params.cert_mode.clone(),
+        Some(certs_dir.clone()),
+        sans.clone(),
+    )
+    .with_passphrase(params.key_passphrase_env, params.key_passphrase_file);
+    // Load public certificate and private key, plus the per hostname (SNI) pairs.
+    let certified_key = load_certified_key(&impl_certs).await?;
+    let hosts = params.cert_hosts.unwrap_or_default();
+    let host_keys = load_host_keys(&hosts, impl_certs.passphrase()?.as_deref())?;
+    for hostname in host_keys.keys() {
+        log::info!("serving certificate for host {}", hostname);
+    }
+    let resolver = Arc::new(CertResolver::new(certified_key, host_keys));
+    // Reload certificates on SIGHUP or when the files change.
+    tokio::spawn(watch_certificates(
+        impl_certs,
+        hosts,
+        resolver.clone(),
+        params.cert_reload_interval.unwrap_or(30),
+    ));
+    // Obtain and renew the certificate from the acme directory.
+    let mut alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+    if params.cert_mode == "acme" {
+        let acme = params
+            .acme
+            .ok_or("cert_mode acme requires the acme config section")?;
+        if sans.is_empty() {
+            return Err("cert_mode acme requires the domains in cert_sans".into());
+        }
+        if acme.challenge.as_deref() == Some("tls-alpn-01") {
+            alpn_protocols.push(ACME_TLS_ALPN.to_vec());
+        }
+        tokio::spawn(watch_acme(acme, certs_dir, sans, resolver.clone()));
+    }
     // Build TLS configuration.
-    let mut server_config = ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(certs, key)
-        .map_err(|e| error(e.to_string()))?;
-    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
+    let builder = match params.client_ca {
+        Some(client_ca) => {
+            let mode = params.client_auth.unwrap_or("required".to_string());
+            log::info!("client certificate authentication {} ({})", mode, client_ca);
+            ServerConfig::builder().with_client_cert_verifier(client_verifier(client_ca, mode)?)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let mut server_config = builder.with_cert_resolver(resolver);
+    server_config.alpn_protocols = alpn_protocols;
     let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
-    let service = service_fn(ai_service);
-
-    loop {
-        let (tcp_stream, _remote_addr) = incoming.accept().await?;
-        let tls_acceptor = tls_acceptor.clone();
-        tokio::spawn(async move {
-            let tls_stream = match tls_acceptor.accept(tcp_stream).await {
-                Ok(tls_stream) => tls_stream,
-                Err(err) => {
-                    log::error!("failed to perform tls handshake: {err:#}");
-                    return;
-                }
-            };
-            if let Err(err) = Builder::new(TokioExecutor::new())
-                .serve_connection(TokioIo::new(tls_stream), service)
-                .await
-            {
-                log::error!("failed to serve connection: {err:#}");
-            }
-        });
+    serve(
+        listeners,
+        Some(tls_acceptor),
+        redirects,
+        trusted_proxies,
+        limits,
+        &params.name,
+        params.shutdown_timeout.unwrap_or(30),
+    )
+    .await;
+    Ok(())
+}
+
+// runs the accept loop of every listener until SIGTERM/SIGINT, then waits (up to
+// shutdown_timeout seconds) for the in-flight requests to finish, the redirect listeners
+// share the connection limit, timeouts and shutdown
+async fn serve(
+    listeners: Vec<Listener>,
+    tls_acceptor: Option<TlsAcceptor>,
+    redirects: Vec<(TcpListener, String)>,
+    trusted_proxies: Arc<Vec<String>>,
+    limits: Limits,
+    name: &str,
+    shutdown_timeout: u64,
+) {
+    let connections = Arc::new(Semaphore::new(limits.max_connections));
+    let graceful = Arc::new(GracefulShutdown::new());
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let scheme = match tls_acceptor {
+        Some(_) => "https",
+        None => "http",
+    };
+    let mut tasks = vec![];
+    for listener in listeners {
+        log::info!("starting {} on {}://{}", name, scheme, listener.describe());
+        let serve = Serve::Console {
+            tls_acceptor: tls_acceptor.clone(),
+            trusted_proxies: trusted_proxies.clone(),
+        };
+        tasks.push(tokio::spawn(accept_loop(
+            listener,
+            serve,
+            limits,
+            connections.clone(),
+            graceful.clone(),
+            shutdown_rx.clone(),
+        )));
+    }
+    for (redirect, https_port) in redirects {
+        let listener = Listener::Tcp(redirect);
+        log::info!("redirecting http://{} to https", listener.describe());
+        tasks.push(tokio::spawn(accept_loop(
+            listener,
+            Serve::Redirect(https_port),
+            limits,
+            connections.clone(),
+            graceful.clone(),
+            shutdown_rx.clone(),
+        )));
+    }
+    shutdown_signal().await;
+    // stop accepting, then let the open connections finish
+    let _ = shutdown_tx.send(());
+    for task in tasks {
+        let _ = task.await;
+    }
+    let Ok(graceful) = Arc::try_unwrap(graceful) else {
+        return;
+    };
+    log::info!(
+        "shutting down, waiting up to {}s for {} connection(s)",
+        shutdown_timeout,
+        graceful.count()
+    );
+    // trees are opened per request and closed by the handlers,
+    // once the requests are done no tree is left open
+    match tokio::time::timeout(Duration::from_secs(shutdown_timeout), graceful.shutdown()).await {
+        Ok(()) => log::info!("all connections closed"),
+        Err(_) => log::warn!("shutdown timeout reached, closing the remaining connections"),
     }
 }
@@ -0,0 +1,219 @@
+use crate::certs::client::{ClientUser, client_user};
+use crate::handlers::proxy::remote_client;
+use crate::handlers::service::{ai_service, redirect_service};
+use crate::server::limits::{Activity, ActivityIo, Limits};
+use crate::server::listener::Listener;
+use custom_logger as log;
+use http::Request;
+use hyper::body::Incoming;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::server::graceful::{GracefulShutdown, Watcher};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore, watch};
+use tokio_rustls::TlsAcceptor;
+
+// unix socket peers are local, they show up as 127.0.0.1
+const UNIX_PEER: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// what the connections of a listener are served with
+#[derive(Clone)]
+pub enum Serve {
+    // the console, over tls when there is an acceptor, the forwarded headers are
+    // only taken from the trusted proxies
+    Console {
+        tls_acceptor: Option<TlsAcceptor>,
+        trusted_proxies: Arc<Vec<String>>,
+    },
+    // redirect_port, plain http visitors are sent to the https console on this port
+    Redirect(String),
+}
+
+/// accepts connections on a listener until shutdown, the console and redirect listeners
+/// share the connection limit, timeouts and graceful shutdown
+pub async fn accept_loop(
+    listener: Listener,
+    serve: Serve,
+    limits: Limits,
+    connections: Arc<Semaphore>,
+    graceful: Arc<GracefulShutdown>,
+    mut shutdown: watch::Receiver<()>,
+) {
+    loop {
+        // at max_connections new connections wait in the listen backlog
+        let permit = tokio::select! {
+            permit = connections.clone().acquire_owned() => match permit {
+                Ok(permit) => permit,
+                Err(_) => return,
+            },
+            _ = shutdown.changed() => return,
+        };
+        let accepted = match &listener {
+            Listener::Tcp(listener) => tokio::select! {
+                res = listener.accept() => res.map(|(stream, remote_addr)| {
+                    tokio::spawn(handle_connection(
+                        stream,
+                        remote_addr,
+                        serve.clone(),
+                        limits,
+                        graceful.watcher(),
+                        permit,
+                    ));
+                }),
+                _ = shutdown.changed() => return,
+            },
+            Listener::Unix(listener) => tokio::select! {
+                res = listener.accept() => res.map(|(stream, _)| {
+                    tokio::spawn(handle_connection(
+                        stream,
+                        UNIX_PEER,
+                        serve.clone(),
+                        limits,
+                        graceful.watcher(),
+                        permit,
+                    ));
+                }),
+                _ = shutdown.changed() => return,
+            },
+        };
+        if let Err(err) = accepted {
+            // e.g. out of file descriptors, back off instead of spinning
+            log::error!("failed to accept connection: {err:#}");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+}
+
+// the permit is held until the connection closes
+async fn handle_connection<I>(
+    io: I,
+    remote_addr: SocketAddr,
+    serve: Serve,
+    limits: Limits,
+    watcher: Watcher,
+    _permit: OwnedSemaphorePermit,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let tls_acceptor = match &serve {
+        Serve::Console {
+            tls_acceptor: Some(tls_acceptor),
+            ..
+        } => tls_acceptor.clone(),
+        _ => {
+            serve_connection(io, remote_addr, "http", None, serve, limits, watcher).await;
+            return;
+        }
+    };
+    let tls_stream =
+        match tokio::time::timeout(limits.handshake_timeout, tls_acceptor.accept(io)).await {
+            Ok(Ok(tls_stream)) => tls_stream,
+            Ok(Err(err)) => {
+                log::error!("failed to perform tls handshake: {err:#}");
+                return;
+            }
+            Err(_) => {
+                log::debug!("tls handshake from {} timed out", remote_addr);
+                return;
+            }
+        };
+    // map the verified client certificate (if any) to a console user
+    let user = tls_stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(client_user);
+    serve_connection(
+        tls_stream,
+        remote_addr,
+        "https",
+        user,
+        serve,
+        limits,
+        watcher,
+    )
+    .await;
+}
+
+// serves the console on an accepted connection, the remote client (and certificate user)
+// is passed to the handlers in the request extensions
+async fn serve_connection<I>(
+    io: I,
+    remote_addr: SocketAddr,
+    scheme: &'static str,
+    user: Option<ClientUser>,
+    serve: Serve,
+    limits: Limits,
+    watcher: Watcher,
+) where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let activity = Activity::new();
+    let service_activity = activity.clone();
+    let service = service_fn(move |mut req: Request<Incoming>| {
+        let redirect = match &serve {
+            Serve::Console {
+                trusted_proxies, ..
+            } => {
+                let remote = remote_client(remote_addr, scheme, req.headers(), trusted_proxies);
+                req.extensions_mut().insert(remote);
+                if let Some(user) = user.clone() {
+                    req.extensions_mut().insert(user);
+                }
+                None
+            }
+            Serve::Redirect(https_port) => Some(https_port.clone()),
+        };
+        let in_flight = service_activity.request();
+        async move {
+            let response = match redirect {
+                Some(https_port) => redirect_service(req, https_port).await,
+                None => ai_service(req, limits).await,
+            };
+            drop(in_flight);
+            response
+        }
+    });
+    let mut builder = Builder::new(TokioExecutor::new());
+    // the header read and keep-alive deadlines are enforced by activity, hyper's
+    // header_read_timeout would also cut the keep-alive wait short
+    builder
+        .http1()
+        .keep_alive(!limits.keep_alive_timeout.is_zero());
+    // on shutdown the connection finishes its in-flight request and closes
+    let conn = builder
+        .serve_connection(TokioIo::new(ActivityIo::new(io, activity.clone())), service)
+        .into_owned();
+    tokio::select! {
+        res = watcher.watch(conn) => {
+            if let Err(err) = res {
+                log::error!("failed to serve connection: {err:#}");
+            }
+        }
+        _ = activity.expired(limits) => {
+            log::debug!("closing idle connection from {}", remote_addr);
+        }
+    }
+}
+
+/// completes on SIGTERM or SIGINT
+pub async fn shutdown_signal() {
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            log::error!("unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => log::info!("received SIGINT"),
+        _ = sigterm.recv() => log::info!("received SIGTERM"),
+    }
+}
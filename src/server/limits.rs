@@ -0,0 +1,197 @@
+use crate::config::process::Parameters;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// connection limits and timeouts, unset config values fall back to the defaults below
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    pub max_body_size: u64,
+    pub handshake_timeout: Duration,
+    pub header_read_timeout: Duration,
+    pub keep_alive_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub max_connections: usize,
+}
+
+impl Limits {
+    pub fn new(params: &Parameters) -> Self {
+        Limits {
+            max_body_size: params.max_body_size.unwrap_or(10 * 1024 * 1024),
+            handshake_timeout: Duration::from_secs(params.handshake_timeout.unwrap_or(10)),
+            header_read_timeout: Duration::from_secs(params.header_read_timeout.unwrap_or(10)),
+            keep_alive_timeout: Duration::from_secs(params.keep_alive_timeout.unwrap_or(60)),
+            idle_timeout: Duration::from_secs(params.idle_timeout.unwrap_or(60)),
+            max_connections: params.max_connections.unwrap_or(1024),
+        }
+    }
+}
+
+// pending_since when no request is being read
+const NONE: u64 = u64::MAX;
+
+// how often a connection with a request being served is looked at again
+const IN_FLIGHT_CHECK: Duration = Duration::from_secs(1);
+
+/// last read/write on a connection and the number of requests being served on it
+#[derive(Clone)]
+pub struct Activity(Arc<ActivityState>);
+
+// times are milliseconds since started
+struct ActivityState {
+    started: Instant,
+    last: AtomicU64,
+    // first byte of a request whose headers are still being read
+    pending_since: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+/// held while a request is served, the connection is not idle until it is dropped
+pub struct InFlight(Activity);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+        self.0.write();
+    }
+}
+
+impl Activity {
+    // the first request is pending from the moment the client connects
+    pub fn new() -> Self {
+        Activity(Arc::new(ActivityState {
+            started: Instant::now(),
+            last: AtomicU64::new(0),
+            pending_since: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+        }))
+    }
+
+    fn now(&self) -> u64 {
+        self.0.started.elapsed().as_millis() as u64
+    }
+
+    fn read(&self) {
+        let now = self.now();
+        self.0.last.store(now, Ordering::Relaxed);
+        if self.0.in_flight.load(Ordering::Relaxed) == 0 {
+            let _ = self.0.pending_since.compare_exchange(
+                NONE,
+                now,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn write(&self) {
+        self.0.last.store(self.now(), Ordering::Relaxed);
+    }
+
+    /// called once the request headers are read
+    pub fn request(&self) -> InFlight {
+        self.0.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.0.pending_since.store(NONE, Ordering::Relaxed);
+        self.write();
+        InFlight(self.clone())
+    }
+
+    /// completes once a deadline passes: the headers of a request have to arrive within
+    /// header_read_timeout (slowloris) and an idle connection is kept for keep_alive_timeout,
+    /// a request being served has no deadline here (a slow ai backend call does no i/o on
+    /// the connection), its body is read with idle_timeout by the handler
+    pub async fn expired(&self, limits: Limits) {
+        loop {
+            if self.0.in_flight.load(Ordering::Relaxed) > 0 {
+                tokio::time::sleep(IN_FLIGHT_CHECK).await;
+                continue;
+            }
+            let pending_since = self.0.pending_since.load(Ordering::Relaxed);
+            let (since, timeout) = if pending_since != NONE {
+                (pending_since, limits.header_read_timeout)
+            } else if limits.keep_alive_timeout.is_zero() {
+                // keep-alive is off, hyper closes the connection after the response
+                (self.0.last.load(Ordering::Relaxed), limits.idle_timeout)
+            } else {
+                (
+                    self.0.last.load(Ordering::Relaxed),
+                    limits.keep_alive_timeout,
+                )
+            };
+            let elapsed = Duration::from_millis(self.now().saturating_sub(since));
+            if elapsed >= timeout {
+                return;
+            }
+            tokio::time::sleep(timeout - elapsed).await;
+        }
+    }
+}
+
+/// records every read and write in the connection's activity
+pub struct ActivityIo<I> {
+    io: I,
+    activity: Activity,
+}
+
+impl<I> ActivityIo<I> {
+    pub fn new(io: I, activity: Activity) -> Self {
+        ActivityIo { io, activity }
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for ActivityIo<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let res = Pin::new(&mut self.io).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            self.activity.read();
+        }
+        res
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for ActivityIo<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.io).poll_write(cx, buf);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.write();
+        }
+        res
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let res = Pin::new(&mut self.io).poll_write_vectored(cx, bufs);
+        if matches!(res, Poll::Ready(Ok(n)) if n > 0) {
+            self.activity.write();
+        }
+        res
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,152 @@
+use crate::certs::controller::error;
+use crate::config::process::Listen;
+use custom_logger as log;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use tokio::net::{TcpListener, UnixListener};
+
+// first file descriptor passed by systemd (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: i32 = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub fn describe(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "tcp".to_string(),
+            },
+            Listener::Unix(listener) => match listener.local_addr() {
+                Ok(addr) => match addr.as_pathname() {
+                    Some(path) => format!("unix:{}", path.display()),
+                    None => "unix".to_string(),
+                },
+                Err(_) => "unix".to_string(),
+            },
+        }
+    }
+}
+
+/// binds every configured listener, without a listen list the console listens on 0.0.0.0:{port}
+pub fn bind_listeners(listen: Option<Vec<Listen>>, port: &str) -> io::Result<Vec<Listener>> {
+    let listen = listen.unwrap_or(vec![Listen {
+        address: Some(format!("0.0.0.0:{}", port)),
+        v6only: None,
+        unix: None,
+        mode: None,
+    }]);
+    let mut listeners = vec![];
+    for entry in listen {
+        let listener = match (entry.address, entry.unix) {
+            (Some(address), None) => bind_tcp(&address, entry.v6only.unwrap_or(false))?,
+            (None, Some(path)) => bind_unix(&path, entry.mode)?,
+            _ => {
+                return Err(error(
+                    "each listen entry needs either address or unix".to_string(),
+                ));
+            }
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// plain http listeners for redirect_port, on the addresses (and v6only) of the tcp listeners
+pub fn bind_redirect(listeners: &[Listener], port: &str) -> io::Result<Vec<TcpListener>> {
+    let port: u16 = port
+        .parse()
+        .map_err(|e| error(format!("invalid redirect_port {}: {}", port, e)))?;
+    let mut addresses: Vec<(SocketAddr, bool)> = vec![];
+    for listener in listeners {
+        let Listener::Tcp(listener) = listener else {
+            continue;
+        };
+        let mut addr = listener.local_addr()?;
+        addr.set_port(port);
+        let v6only = addr.is_ipv6() && SockRef::from(listener).only_v6()?;
+        if !addresses.contains(&(addr, v6only)) {
+            addresses.push((addr, v6only));
+        }
+    }
+    let mut redirects = vec![];
+    for (addr, v6only) in addresses {
+        if let Listener::Tcp(listener) = bind_tcp(&addr.to_string(), v6only)? {
+            redirects.push(listener);
+        }
+    }
+    Ok(redirects)
+}
+
+// "[::]:port" accepts ipv4 as well unless v6only is set
+fn bind_tcp(address: &str, v6only: bool) -> io::Result<Listener> {
+    let addr: SocketAddr = address
+        .parse()
+        .map_err(|e| error(format!("invalid listen address {}: {}", address, e)))?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(v6only)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .map_err(|e| error(format!("failed to bind {}: {}", address, e)))?;
+    socket.listen(1024)?;
+    Ok(Listener::Tcp(TcpListener::from_std(socket.into())?))
+}
+
+// owner only unless the listen entry sets a mode (config validation assumes the same default)
+const UNIX_MODE: &str = "600";
+
+// a stale socket from a previous run is replaced, any other file is left alone
+fn bind_unix(path: &str, mode: Option<String>) -> io::Result<Listener> {
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(error(format!("{} exists and is not a socket", path)));
+        }
+        fs::remove_file(path)?;
+    }
+    let listener =
+        UnixListener::bind(path).map_err(|e| error(format!("failed to bind {}: {}", path, e)))?;
+    let mode = mode.unwrap_or(UNIX_MODE.to_string());
+    let mode = u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+        .map_err(|e| error(format!("invalid mode {} for {}: {}", mode, path, e)))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(Listener::Unix(listener))
+}
+
+/// sockets passed by systemd socket activation, they replace the configured listeners
+pub fn systemd_listeners() -> io::Result<Vec<Listener>> {
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(vec![]);
+    }
+    let fds = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|fds| fds.parse::<i32>().ok())
+        .unwrap_or(0);
+    log::info!("using {} socket(s) passed by systemd", fds);
+    let mut listeners = vec![];
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + fds {
+        // SAFETY: systemd hands these descriptors to this process (LISTEN_PID matches)
+        // and nothing else in the process owns them
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        socket.set_nonblocking(true)?;
+        let listener = match socket.local_addr()?.as_socket() {
+            Some(_) => Listener::Tcp(TcpListener::from_std(socket.into())?),
+            None => Listener::Unix(UnixListener::from_std(socket.into())?),
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}